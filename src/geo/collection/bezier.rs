@@ -1,7 +1,8 @@
 use super::ds::BBox;
 use crate::{
-    geo::{Geo, HitResult, HitTemp, Material, Texture, TextureRaw},
+    geo::{Geo, HitResult, HitTemp, Texture, TextureImage, TextureKind},
     linalg::{Ray, Transform, Vct},
+    utils::{image::RGBA, Sampler},
     Deserialize, Flt, Serialize, EPS, PI,
 };
 use serde::de::{self, Deserializer, MapAccess, Visitor};
@@ -73,6 +74,27 @@ impl BezierRotate {
             BBox { max: Vct::new(max_x, max_y, max_x), min: Vct::new(-max_x, min_y, -max_x) };
         Self { point, texture, transform, b2d, bbox }
     }
+
+    // the color of an image texture where r hits at k, at t of the curve turned by (cos, sin)
+    fn lookup(&self, img: &TextureImage, r: &Ray, k: Flt, t: Flt, x: Flt, cs: (Flt, Flt)) -> RGBA {
+        let (u, v) = uv(cs, t);
+        let px = u * img.image.w as Flt;
+        let py = v * img.image.h as Flt;
+        // texels per unit length around the axis and along the curve
+        let (dx, dy) = self.b2d.dp(t);
+        let su = img.image.w as Flt / (2.0 * PI * x.abs().max(EPS));
+        let sv = img.image.h as Flt / (dx * dx + dy * dy).sqrt().max(EPS);
+        img.lookup(px, py, r.footprint(k) * su.max(sv))
+    }
+}
+
+// the angle around the axis and the parameter of the curve
+fn uv((cos, sin): (Flt, Flt), t: Flt) -> (Flt, Flt) {
+    let mut u = cos.clamp(-1.0, 1.0).acos();
+    if sin < 0.0 {
+        u += PI;
+    }
+    (u / PI / 2.0, t)
 }
 
 impl Geo for BezierRotate {
@@ -124,7 +146,10 @@ impl Geo for BezierRotate {
                         if k > EPS && (ans.is_none() || ans.unwrap().0 > k) {
                             let px = o.x + k * d.x;
                             let pz = o.z + k * d.z;
-                            if (px * px + pz * pz - xx).abs() < EPS {
+                            let cs = if x.abs() < EPS { (1.0, 0.0) } else { (px / x, pz / x) };
+                            if (px * px + pz * pz - xx).abs() < EPS
+                                && self.texture.opaque(|img| self.lookup(img, r, k, t, x, cs))
+                            {
                                 ans = Some((k, Some((0, t, x))));
                             }
                         }
@@ -169,46 +194,55 @@ impl Geo for BezierRotate {
         let (o, d) = (self.transform.inv * r.origin, (self.transform.inv % r.direct).norm());
         let k = tmp.0;
         let (_, t, x) = tmp.1.unwrap();
-        let (mut cos, mut sin) = (1.0, 0.0);
-        let norm = (self.transform.value
+        let (mut cos, mut sin): (Flt, Flt) = (1.0, 0.0);
+        let (mut dt, mut dd) = (Vct::zero(), Vct::zero());
+        let mut norm = (self.transform.value
             % if x.abs() < EPS {
                 Vct::new(0.0, -d.y, 0.0)
             } else {
                 cos = (o.x + k * d.x) / x;
                 sin = (o.z + k * d.z) / x;
                 let (dx, dy) = self.b2d.dp(t);
-                let dt = Vct::new(cos * dx, dy, sin * dx);
-                let dd = Vct::new(-sin * x, 0.0, cos * x);
+                dt = Vct::new(cos * dx, dy, sin * dx);
+                dd = Vct::new(-sin * x, 0.0, cos * x);
                 dt % dd
             })
         .norm();
+        let pos = r.origin + r.direct * k;
+        if let Some(rl) = self.texture.relief() {
+            // u turns backwards where it is shifted by PI
+            let dpdu = dd * if sin < 0.0 { -2.0 * PI } else { 2.0 * PI };
+            let (dpdu, dpdv) = (self.transform.value % dpdu, self.transform.value % dt);
+            norm = rl.perturb(norm, uv((cos, sin), t), dpdu, dpdv);
+        }
         HitResult {
-            pos: r.origin + r.direct * k,
+            pos,
             norm,
-            texture: match self.texture {
-                Texture::Raw(ref raw) => *raw,
-                Texture::Image(ref img) => {
-                    if cos < -1.0 {
-                        cos = -1.0;
-                    } else if cos > 1.0 {
-                        cos = 1.0;
-                    }
-                    let mut px = cos.acos();
-                    if sin < 0.0 {
-                        px += PI;
-                    }
-                    px = (px / PI / 2.0) * img.image.w as Flt;
-                    let py = t * img.image.h as Flt;
-                    let col = img.image.get_repeat(px as isize, py as isize);
-                    TextureRaw {
-                        emission: Vct::zero(),
-                        color: Vct::new(col.0, col.1, col.2),
-                        material: if col.3 > 0.0 { Material::Diffuse } else { img.material },
-                    }
+            texture: match self.texture.kind {
+                TextureKind::Raw(ref raw) => *raw,
+                TextureKind::Image(ref img) => img.texel(self.lookup(img, r, k, t, x, (cos, sin))),
+                TextureKind::Procedural(ref pr) => {
+                    pr.texel(self.transform.inv * pos, uv((cos, sin), t))
                 }
             },
         }
     }
+
+    // uniform in the angle and the parameter of the curve, the area around is 2 pi x |dp|
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        let (ang, t) = (2.0 * PI * rng.gen(), rng.gen());
+        let ((x, y), (dx, dy)) = (self.b2d.p(t), self.b2d.dp(t));
+        let (cos, sin) = (ang.cos(), ang.sin());
+        let n = if x.abs() < EPS {
+            Vct::new(0.0, 1.0, 0.0) // on the axis, where there is no area
+        } else {
+            (Vct::new(cos * dx, dy, sin * dx) % Vct::new(-sin * x, 0.0, cos * x)).norm()
+        };
+        let o = Vct::new(x * cos, y, x * sin) + n;
+        let r = Ray::new(self.transform.value * o, (self.transform.value % -n).norm());
+        let area = 2.0 * PI * x.abs() * (dx * dx + dy * dy).sqrt();
+        Some((self.hit(&r, (1.0, Some((0, t, x)))), area))
+    }
 }
 
 impl Serialize for BezierRotate {
@@ -380,15 +414,15 @@ mod tests {
         );
     }
 
-    use crate::geo::Material;
+    use crate::geo::{Material, TextureRaw};
     #[test]
     fn check_norm() {
         let drawit = |point: Vec<(Flt, Flt)>, r: &Ray| {
-            let texture = Texture::Raw(TextureRaw::new(
+            let texture = Texture::new(TextureKind::Raw(TextureRaw::new(
                 Vct::zero(),
                 Vct::new(1.0, 1.0, 1.0),
                 Material::Specular,
-            ));
+            )));
             let transform = Transform::new(Vec::new());
             let b2d = BezierRotate::new(point, texture, transform);
             let tmp = b2d.hit_t(&r);
@@ -401,7 +435,7 @@ mod tests {
             // &Ray::new(Vct::new(0.001, 50.0, 0.0), Vct::new(0.0, -1.0, 0.0).norm()),
             // &Ray::new(Vct::new(5.0, 5.0, 50.0), Vct::new(0.0, 0.0, -1.0).norm()),
             // &Ray::new(Vct::new(15.0, 20.0, 0.0), Vct::new(-1.0, -1.0, 0.0).norm()),
-            //&Ray::new(Vct::new(0.0, 10.0, 50.0), Vct::new(0.0, 0.0, -1.0).norm()),
+            &Ray::new(Vct::new(0.0, 10.0, 50.0), Vct::new(0.0, 0.0, -1.0).norm()),
         );
     }
 }
@@ -0,0 +1,129 @@
+use super::disk::{cap, cap_point, cap_uv};
+use super::local::{self, quadratic, turn, Local, NONE};
+use crate::{
+    geo::{Geo, HitResult, HitTemp, Texture},
+    linalg::{Ray, Transform, Vct},
+    utils::Sampler,
+    Deserialize, Flt, Serialize, EPS, PI,
+};
+
+fn default_caps() -> bool {
+    true
+}
+
+// With its base of radius at the origin and its apex at height along z. The side has u around
+// z and v along it, the base projects the texture along z.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cone {
+    pub radius: Flt,
+    pub height: Flt,
+    #[serde(default = "default_caps")]
+    pub caps: bool, // closed by the base
+    pub texture: Texture,
+    pub transform: Transform,
+}
+
+impl Cone {
+    pub fn new(
+        radius: Flt,
+        height: Flt,
+        caps: bool,
+        texture: Texture,
+        transform: Transform,
+    ) -> Self {
+        Self { radius, height, caps, texture, transform }
+    }
+
+    // whether p is closer to the base than to the side
+    fn on_cap(&self, p: Vct) -> bool {
+        let side = (p.x * p.x + p.y * p.y).sqrt() - self.radius * (1.0 - p.z / self.height);
+        self.caps && p.z.abs() < side.abs()
+    }
+}
+
+impl Local for Cone {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    // x^2 + y^2 = (k (height - z))^2 with z between the base and the apex
+    fn roots(&self, o: Vct, d: Vct) -> [Flt; 4] {
+        let mut ret = NONE;
+        let k = self.radius / self.height;
+        let (k2, w) = (k * k, self.height - o.z);
+        let a = d.x * d.x + d.y * d.y - k2 * d.z * d.z;
+        let b = 2.0 * (o.x * d.x + o.y * d.y + k2 * w * d.z);
+        let c = o.x * o.x + o.y * o.y - k2 * w * w;
+        if let Some((t0, t1)) = quadratic(a, b, c) {
+            let inside = |t: Flt| (0.0..=self.height).contains(&(o.z + d.z * t));
+            ret[0] = if inside(t0) { t0 } else { Flt::INFINITY };
+            ret[1] = if inside(t1) { t1 } else { Flt::INFINITY };
+        }
+        if self.caps {
+            ret[2] = cap(o, d, 0.0, self.radius);
+        }
+        ret
+    }
+
+    fn normal(&self, p: Vct) -> Vct {
+        if self.on_cap(p) {
+            return Vct::new(0.0, 0.0, -1.0);
+        }
+        let k = self.radius / self.height;
+        let n = Vct::new(p.x, p.y, k * k * (self.height - p.z));
+        if n.len2() < EPS * EPS {
+            Vct::new(0.0, 0.0, 1.0)
+        } else {
+            n
+        }
+    }
+
+    fn uv(&self, p: Vct) -> ((Flt, Flt), Vct, Vct) {
+        if self.on_cap(p) {
+            return cap_uv(p, self.radius);
+        }
+        let u = turn(p.x, p.y);
+        let (sin, cos) = (2.0 * PI * u).sin_cos();
+        let dpdv = Vct::new(-cos * self.radius, -sin * self.radius, self.height);
+        ((u, p.z / self.height), Vct::new(-p.y, p.x, 0.0) * (2.0 * PI), dpdv)
+    }
+
+    // the side takes the first half of u and the base the other
+    fn point(&self, u: Flt, v: Flt) -> (Vct, Vct, Vct) {
+        let (side, u, k) = if !self.caps {
+            (true, u, 1.0)
+        } else if u < 0.5 {
+            (true, u * 2.0, 2.0)
+        } else {
+            (false, u * 2.0 - 1.0, 2.0)
+        };
+        let (p, dpdu, dpdv) = if side {
+            let (sin, cos) = (2.0 * PI * u).sin_cos();
+            let r = self.radius * (1.0 - v);
+            let p = Vct::new(cos * r, sin * r, v * self.height);
+            let dpdu = Vct::new(-sin, cos, 0.0) * (2.0 * PI * r);
+            (p, dpdu, Vct::new(-cos * self.radius, -sin * self.radius, self.height))
+        } else {
+            cap_point(u, v, 0.0, self.radius)
+        };
+        (p, dpdu * k, dpdv)
+    }
+}
+
+impl Geo for Cone {
+    fn hit_t(&self, r: &Ray) -> Option<HitTemp> {
+        local::hit_t(self, r)
+    }
+
+    fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
+        local::hit(self, r, tmp)
+    }
+
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        local::sample(self, rng)
+    }
+}
@@ -0,0 +1,111 @@
+use super::local::{self, Local, NONE};
+use crate::{
+    geo::{Geo, HitResult, HitTemp, Texture},
+    linalg::{Ray, Transform, Vct},
+    utils::Sampler,
+    Deserialize, Flt, Serialize, EPS,
+};
+
+// A box of the size along the axes centered at the origin. Every face has the whole texture,
+// with u along the next axis and v along the one after it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cuboid {
+    pub size: Vct,
+    pub texture: Texture,
+    pub transform: Transform,
+}
+
+fn axis(i: usize) -> Vct {
+    match i {
+        0 => Vct::new(1.0, 0.0, 0.0),
+        1 => Vct::new(0.0, 1.0, 0.0),
+        _ => Vct::new(0.0, 0.0, 1.0),
+    }
+}
+
+impl Cuboid {
+    pub fn new(size: Vct, texture: Texture, transform: Transform) -> Self {
+        Self { size, texture, transform }
+    }
+
+    // the axis of the face p is on
+    fn face(&self, p: Vct) -> usize {
+        let k = |i: usize| (p[i] / self.size[i]).abs();
+        if k(0) >= k(1) && k(0) >= k(2) {
+            0
+        } else if k(1) >= k(2) {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+impl Local for Cuboid {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    // between the slabs of the three axes
+    fn roots(&self, o: Vct, d: Vct) -> [Flt; 4] {
+        let (mut near, mut far) = (-Flt::INFINITY, Flt::INFINITY);
+        for i in 0..3 {
+            let h = self.size[i] * 0.5;
+            if d[i].abs() < EPS {
+                if o[i].abs() > h {
+                    return NONE;
+                }
+                continue;
+            }
+            let (t0, t1) = ((-h - o[i]) / d[i], (h - o[i]) / d[i]);
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        if near > far {
+            return NONE;
+        }
+        [near, far, Flt::INFINITY, Flt::INFINITY]
+    }
+
+    fn normal(&self, p: Vct) -> Vct {
+        let i = self.face(p);
+        axis(i) * p[i].signum()
+    }
+
+    fn uv(&self, p: Vct) -> ((Flt, Flt), Vct, Vct) {
+        let i = self.face(p);
+        let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+        let uv = (p[j] / self.size[j] + 0.5, p[k] / self.size[k] + 0.5);
+        (uv, axis(j) * self.size[j], axis(k) * self.size[k])
+    }
+
+    // u picks one of the six faces first
+    fn point(&self, u: Flt, v: Flt) -> (Vct, Vct, Vct) {
+        let f = ((u * 6.0) as usize).min(5);
+        let (i, u) = (f / 2, u * 6.0 - f as Flt);
+        let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+        let sign = 0.5 - (f % 2) as Flt;
+        let p = axis(i) * (self.size[i] * sign)
+            + axis(j) * ((u - 0.5) * self.size[j])
+            + axis(k) * ((v - 0.5) * self.size[k]);
+        (p, axis(j) * (self.size[j] * 6.0), axis(k) * self.size[k])
+    }
+}
+
+impl Geo for Cuboid {
+    fn hit_t(&self, r: &Ray) -> Option<HitTemp> {
+        local::hit_t(self, r)
+    }
+
+    fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
+        local::hit(self, r, tmp)
+    }
+
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        local::sample(self, rng)
+    }
+}
@@ -0,0 +1,130 @@
+use super::disk::{cap, cap_point, cap_uv};
+use super::local::{self, quadratic, turn, Local, NONE};
+use crate::{
+    geo::{Geo, HitResult, HitTemp, Texture},
+    linalg::{Ray, Transform, Vct},
+    utils::Sampler,
+    Deserialize, Flt, Serialize, PI,
+};
+
+fn default_caps() -> bool {
+    true
+}
+
+// Around z from 0 to height. The side has u around z and v along it, the caps project the
+// texture along z.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cylinder {
+    pub radius: Flt,
+    pub height: Flt,
+    #[serde(default = "default_caps")]
+    pub caps: bool, // closed by disks at both ends
+    pub texture: Texture,
+    pub transform: Transform,
+}
+
+impl Cylinder {
+    pub fn new(
+        radius: Flt,
+        height: Flt,
+        caps: bool,
+        texture: Texture,
+        transform: Transform,
+    ) -> Self {
+        Self { radius, height, caps, texture, transform }
+    }
+
+    // the height of the cap p is on, if it is closer to one than to the side
+    fn on_cap(&self, p: Vct) -> Option<Flt> {
+        if !self.caps {
+            return None;
+        }
+        let side = ((p.x * p.x + p.y * p.y).sqrt() - self.radius).abs();
+        let z = if p.z < self.height * 0.5 { 0.0 } else { self.height };
+        if (p.z - z).abs() < side {
+            Some(z)
+        } else {
+            None
+        }
+    }
+}
+
+impl Local for Cylinder {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    fn roots(&self, o: Vct, d: Vct) -> [Flt; 4] {
+        let mut ret = NONE;
+        let a = d.x * d.x + d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.y * d.y);
+        let c = o.x * o.x + o.y * o.y - self.radius * self.radius;
+        if let Some((t0, t1)) = quadratic(a, b, c) {
+            let inside = |t: Flt| (0.0..=self.height).contains(&(o.z + d.z * t));
+            ret[0] = if inside(t0) { t0 } else { Flt::INFINITY };
+            ret[1] = if inside(t1) { t1 } else { Flt::INFINITY };
+        }
+        if self.caps {
+            ret[2] = cap(o, d, 0.0, self.radius);
+            ret[3] = cap(o, d, self.height, self.radius);
+        }
+        ret
+    }
+
+    fn normal(&self, p: Vct) -> Vct {
+        match self.on_cap(p) {
+            Some(z) if z <= 0.0 => Vct::new(0.0, 0.0, -1.0),
+            Some(_) => Vct::new(0.0, 0.0, 1.0),
+            None => Vct::new(p.x, p.y, 0.0),
+        }
+    }
+
+    fn uv(&self, p: Vct) -> ((Flt, Flt), Vct, Vct) {
+        if self.on_cap(p).is_some() {
+            return cap_uv(p, self.radius);
+        }
+        let uv = (turn(p.x, p.y), p.z / self.height);
+        (uv, Vct::new(-p.y, p.x, 0.0) * (2.0 * PI), Vct::new(0.0, 0.0, self.height))
+    }
+
+    // the side takes the first half of u and the caps a quarter each
+    fn point(&self, u: Flt, v: Flt) -> (Vct, Vct, Vct) {
+        let (part, u, k) = if !self.caps {
+            (0, u, 1.0)
+        } else if u < 0.5 {
+            (0, u * 2.0, 2.0)
+        } else {
+            let i = ((u * 4.0) as usize).min(3);
+            (i - 1, u * 4.0 - i as Flt, 4.0)
+        };
+        let (p, dpdu, dpdv) = match part {
+            0 => {
+                let (sin, cos) = (2.0 * PI * u).sin_cos();
+                let p = Vct::new(cos * self.radius, sin * self.radius, v * self.height);
+                let dpdu = Vct::new(-sin, cos, 0.0) * (2.0 * PI * self.radius);
+                (p, dpdu, Vct::new(0.0, 0.0, self.height))
+            },
+            1 => cap_point(u, v, 0.0, self.radius),
+            _ => cap_point(u, v, self.height, self.radius),
+        };
+        (p, dpdu * k, dpdv)
+    }
+}
+
+impl Geo for Cylinder {
+    fn hit_t(&self, r: &Ray) -> Option<HitTemp> {
+        local::hit_t(self, r)
+    }
+
+    fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
+        local::hit(self, r, tmp)
+    }
+
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        local::sample(self, rng)
+    }
+}
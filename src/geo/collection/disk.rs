@@ -0,0 +1,91 @@
+use super::local::{self, Local, NONE};
+use crate::{
+    geo::{Geo, HitResult, HitTemp, Texture},
+    linalg::{Ray, Transform, Vct},
+    utils::Sampler,
+    Deserialize, Flt, Serialize, EPS, PI,
+};
+
+// centered at the origin and facing z, textures are projected along z onto it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Disk {
+    pub radius: Flt,
+    pub texture: Texture,
+    pub transform: Transform,
+}
+
+impl Disk {
+    pub fn new(radius: Flt, texture: Texture, transform: Transform) -> Self {
+        Self { radius, texture, transform }
+    }
+}
+
+// where o + d t crosses the disk of radius at height z, also the caps of other shapes
+pub fn cap(o: Vct, d: Vct, z: Flt, radius: Flt) -> Flt {
+    if d.z.abs() < EPS {
+        return Flt::INFINITY;
+    }
+    let t = (z - o.z) / d.z;
+    let p = o + d * t;
+    if p.x * p.x + p.y * p.y > radius * radius {
+        return Flt::INFINITY;
+    }
+    t
+}
+
+// the uv of the disk of radius at p and its derivatives
+pub fn cap_uv(p: Vct, radius: Flt) -> ((Flt, Flt), Vct, Vct) {
+    let s = radius * 2.0;
+    ((p.x / s + 0.5, p.y / s + 0.5), Vct::new(s, 0.0, 0.0), Vct::new(0.0, s, 0.0))
+}
+
+// a point of the disk of radius at height z, uniform in (u, v)
+pub fn cap_point(u: Flt, v: Flt, z: Flt, radius: Flt) -> (Vct, Vct, Vct) {
+    let (r, phi) = (radius * v.max(EPS).sqrt(), 2.0 * PI * u);
+    let (sin, cos) = phi.sin_cos();
+    let dpdu = Vct::new(-r * sin, r * cos, 0.0) * (2.0 * PI);
+    let dpdv = Vct::new(cos, sin, 0.0) * (radius * 0.5 / v.max(EPS).sqrt());
+    (Vct::new(r * cos, r * sin, z), dpdu, dpdv)
+}
+
+impl Local for Disk {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    fn roots(&self, o: Vct, d: Vct) -> [Flt; 4] {
+        let mut ret = NONE;
+        ret[0] = cap(o, d, 0.0, self.radius);
+        ret
+    }
+
+    fn normal(&self, _p: Vct) -> Vct {
+        Vct::new(0.0, 0.0, 1.0)
+    }
+
+    fn uv(&self, p: Vct) -> ((Flt, Flt), Vct, Vct) {
+        cap_uv(p, self.radius)
+    }
+
+    fn point(&self, u: Flt, v: Flt) -> (Vct, Vct, Vct) {
+        cap_point(u, v, 0.0, self.radius)
+    }
+}
+
+impl Geo for Disk {
+    fn hit_t(&self, r: &Ray) -> Option<HitTemp> {
+        local::hit_t(self, r)
+    }
+
+    fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
+        local::hit(self, r, tmp)
+    }
+
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        local::sample(self, rng)
+    }
+}
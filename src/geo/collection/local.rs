@@ -0,0 +1,261 @@
+use crate::{
+    geo::{HitResult, HitTemp, Texture, TextureImage, TextureKind},
+    linalg::{Ray, Transform, Vct},
+    utils::{image::RGBA, Sampler},
+    Flt, EPS, PI,
+};
+
+// no roots
+pub const NONE: [Flt; 4] = [Flt::INFINITY; 4];
+
+// Shapes intersected in their object space, which their transform maps into the scene. The
+// direction of rays is not normalized there, so distances along them stay the same.
+pub trait Local {
+    fn transform(&self) -> &Transform;
+
+    fn texture(&self) -> &Texture;
+
+    // the distances along o + d t where the line crosses the surface, infinite for the rest
+    fn roots(&self, o: Vct, d: Vct) -> [Flt; 4];
+
+    // the normal out of the surface at p, not normalized
+    fn normal(&self, p: Vct) -> Vct;
+
+    // the uv of textures at p, in [0, 1) over the whole image, and the derivatives of p
+    // along them
+    fn uv(&self, p: Vct) -> ((Flt, Flt), Vct, Vct);
+
+    // the point of the surface at (u, v) in [0, 1) and its derivatives along them, which
+    // cover the whole surface for sampling
+    fn point(&self, u: Flt, v: Flt) -> (Vct, Vct, Vct);
+}
+
+// the roots of a x^2 + b x + c, the smaller first
+pub fn quadratic(a: Flt, b: Flt, c: Flt) -> Option<(Flt, Flt)> {
+    if a.abs() < EPS * EPS {
+        if b.abs() < EPS * EPS {
+            return None;
+        }
+        return Some((-c / b, Flt::INFINITY));
+    }
+    let det = b * b - 4.0 * a * c;
+    if det < 0.0 {
+        return None;
+    }
+    // without cancelling out the smaller root
+    let q = -0.5 * (b + b.signum() * det.sqrt());
+    let (x0, x1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    Some((x0.min(x1), x0.max(x1)))
+}
+
+// the angle of (x, y) around z as a fraction of a turn, in [0, 1)
+pub fn turn(x: Flt, y: Flt) -> Flt {
+    let a = y.atan2(x) / (2.0 * PI);
+    if a < 0.0 {
+        a + 1.0
+    } else {
+        a
+    }
+}
+
+// normals go through the inverse transpose
+fn to_world(t: &Transform, n: Vct) -> Vct {
+    let m = &t.inv;
+    Vct::new(
+        m.m00 * n.x + m.m10 * n.y + m.m20 * n.z,
+        m.m01 * n.x + m.m11 * n.y + m.m21 * n.z,
+        m.m02 * n.x + m.m12 * n.y + m.m22 * n.z,
+    )
+}
+
+// the color of an image texture at p, footprint is the width of the ray there in the scene
+fn lookup<T: Local>(s: &T, img: &TextureImage, p: Vct, footprint: Flt) -> RGBA {
+    let ((u, v), dpdu, dpdv) = s.uv(p);
+    let t = s.transform();
+    let (w, h) = (img.image.w as Flt, img.image.h as Flt);
+    // texels along a unit of length in the scene
+    let lu = (t.value % dpdu).len().max(EPS);
+    let lv = (t.value % dpdv).len().max(EPS);
+    img.lookup(u * w, v * h, footprint * (w / lu).max(h / lv))
+}
+
+pub fn hit_t<T: Local>(s: &T, r: &Ray) -> Option<HitTemp> {
+    let t = s.transform();
+    let (o, d) = (t.inv * r.origin, t.inv % r.direct);
+    let mut roots = s.roots(o, d);
+    roots.sort_by(|a, b| a.total_cmp(b));
+    roots
+        .iter()
+        .find(|&&x| {
+            x > EPS
+                && x < Flt::INFINITY
+                && s.texture().opaque(|img| lookup(s, img, o + d * x, r.footprint(x)))
+        })
+        .map(|&x| (x, None))
+}
+
+// the hit at pos, which is p in object space
+fn result<T: Local>(s: &T, pos: Vct, p: Vct, footprint: Flt) -> HitResult {
+    let t = s.transform();
+    let mut norm = to_world(t, s.normal(p)).norm();
+    if let Some(rl) = s.texture().relief() {
+        let (uv, dpdu, dpdv) = s.uv(p);
+        norm = rl.perturb(norm, uv, t.value % dpdu, t.value % dpdv);
+    }
+    HitResult {
+        pos,
+        norm,
+        texture: match s.texture().kind {
+            TextureKind::Raw(ref raw) => *raw,
+            TextureKind::Image(ref img) => img.texel(lookup(s, img, p, footprint)),
+            TextureKind::Procedural(ref pr) => pr.texel(p, s.uv(p).0),
+        },
+    }
+}
+
+pub fn hit<T: Local>(s: &T, r: &Ray, tmp: HitTemp) -> HitResult {
+    let pos = r.origin + r.direct * tmp.0;
+    result(s, pos, s.transform().inv * pos, r.footprint(tmp.0))
+}
+
+// uniform in the parameters of point, the area they stretch over is the inverse pdf
+pub fn sample<T: Local>(s: &T, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+    let t = s.transform();
+    let (p, dpdu, dpdv) = s.point(rng.gen(), rng.gen());
+    let area = ((t.value % dpdu) % (t.value % dpdv)).len();
+    Some((result(s, t.value * p, p, 0.0), area))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Cone, Cuboid, Cylinder, Disk, Rectangle, Sphere, Torus};
+    use super::*;
+    use crate::{geo::Geo, linalg::TransformType, utils::Rng};
+
+    fn diffuse() -> Texture {
+        let raw = r#"{"type": "raw", "emission": {"x": 0, "y": 0, "z": 0},
+            "color": {"x": 1, "y": 1, "z": 1}, "material": "diffuse"}"#;
+        serde_json::from_str(raw).unwrap()
+    }
+
+    fn scale(x: Flt, y: Flt, z: Flt) -> Transform {
+        Transform::new(vec![TransformType::Scale { x, y, z }])
+    }
+
+    // the distance and the normal where the ray from o along d hits s
+    fn check(s: &dyn Geo, o: Vct, d: Vct, t: Flt, n: Vct) {
+        let r = Ray::new(o, d.norm());
+        let tmp = s.hit_t(&r).unwrap();
+        assert!((tmp.0 - t).abs() < 1e-9, "{} {}", tmp.0, t);
+        assert!((s.hit(&r, tmp).norm - n.norm()).len() < 1e-9);
+    }
+
+    #[test]
+    fn shapes() {
+        let z = Vct::new(0.0, 0.0, -1.0);
+        let rect = Rectangle::new(2.0, 1.0, diffuse(), scale(3.0, 1.0, 1.0));
+        check(&rect, Vct::new(2.9, 0.0, 5.0), z, 5.0, Vct::new(0.0, 0.0, 1.0));
+        assert!(rect.hit_t(&Ray::new(Vct::new(3.1, 0.0, 5.0), z)).is_none());
+        let disk = Disk::new(1.0, diffuse(), scale(1.0, 2.0, 1.0));
+        check(&disk, Vct::new(0.0, 1.9, 5.0), z, 5.0, Vct::new(0.0, 0.0, 1.0));
+        let cuboid = Cuboid::new(Vct::one(), diffuse(), scale(2.0, 1.0, 1.0));
+        let x = Vct::new(-1.0, 0.0, 0.0);
+        check(&cuboid, Vct::new(5.0, 0.3, 0.0), x, 4.0, Vct::new(1.0, 0.0, 0.0));
+        // the normal of the side of an elliptic cylinder, from inside
+        let cylinder = Cylinder::new(1.0, 2.0, true, diffuse(), scale(2.0, 1.0, 1.0));
+        let s = 2.0 / (5.0 as Flt).sqrt();
+        let d = Vct::new(1.0, 1.0, 0.0);
+        check(
+            &cylinder,
+            Vct::new(0.0, 0.0, 1.0),
+            d,
+            s * (2.0 as Flt).sqrt(),
+            Vct::new(1.0, 4.0, 0.0),
+        );
+        check(&cylinder, Vct::new(0.5, 0.0, 5.0), z, 3.0, Vct::new(0.0, 0.0, 1.0));
+        let cone = Cone::new(1.0, 1.0, true, diffuse(), Transform::new(vec![]));
+        check(&cone, Vct::new(0.5, 0.0, 5.0), z, 4.5, Vct::new(1.0, 0.0, 1.0));
+        check(&cone, Vct::new(0.5, 0.0, -5.0), -z, 5.0, Vct::new(0.0, 0.0, -1.0));
+        // through the hole of the torus, and onto the top of its tube stretched along z
+        let torus = Torus::new(2.0, 0.5, diffuse(), scale(1.0, 1.0, 2.0));
+        assert!(torus.hit_t(&Ray::new(Vct::new(0.0, 0.0, 5.0), z)).is_none());
+        check(&torus, Vct::new(2.0, 0.0, 5.0), z, 4.0, Vct::new(0.0, 0.0, 1.0));
+        check(&torus, Vct::new(-5.0, 0.0, 0.0), -x, 2.5, Vct::new(-1.0, 0.0, 0.0));
+        // an ellipsoid with its poles turned up to y, half as tall as it is wide
+        let sphere = Sphere::new(
+            2.0,
+            diffuse(),
+            Transform::new(vec![
+                TransformType::Scale { x: 1.0, y: 1.0, z: 0.5 },
+                TransformType::Rotate { axis: String::from("x"), degree: -90.0 },
+            ]),
+        );
+        let y = Vct::new(0.0, -1.0, 0.0);
+        check(&sphere, Vct::new(0.0, 5.0, 0.0), y, 4.0, -y);
+        let s = (3.0 as Flt).sqrt();
+        check(&sphere, Vct::new(0.0, 0.5, 5.0), z, 5.0 - s, Vct::new(0.0, 2.0, s));
+        let uv = |p: Vct| sphere.uv(sphere.transform.inv * p).0;
+        assert!((uv(Vct::new(0.0, 1.0, 0.0)).1 - 1.0).abs() < 1e-9);
+        let (u, v) = uv(Vct::new(-2.0, 0.0, 0.0));
+        assert!((u - 0.5).abs() < 1e-9 && (v - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn areas() {
+        let mut rng = Rng::new(7);
+        let pi = PI;
+        let shapes: Vec<(Box<dyn Geo>, Flt)> = vec![
+            (Box::new(Rectangle::new(2.0, 1.0, diffuse(), scale(3.0, 1.0, 1.0))), 6.0),
+            (Box::new(Disk::new(1.0, diffuse(), scale(2.0, 1.0, 1.0))), 2.0 * pi),
+            (Box::new(Cuboid::new(Vct::new(1.0, 2.0, 3.0), diffuse(), scale(2.0, 1.0, 1.0))), 32.0),
+            (Box::new(Cylinder::new(1.0, 2.0, true, diffuse(), Transform::new(vec![]))), 6.0 * pi),
+            (
+                Box::new(Cone::new(1.0, 1.0, true, diffuse(), Transform::new(vec![]))),
+                pi * (1.0 + (2.0 as Flt).sqrt()),
+            ),
+            (Box::new(Torus::new(2.0, 0.5, diffuse(), Transform::new(vec![]))), 4.0 * pi * pi),
+            (Box::new(Sphere::new(2.0, diffuse(), scale(1.0, 1.0, 1.0))), 16.0 * pi),
+        ];
+        let n = 20000;
+        for (s, area) in shapes.iter() {
+            let sum = (0..n).map(|_| s.sample(&mut rng).unwrap().1).sum::<Flt>();
+            assert!((sum / n as Flt - area).abs() < area * 0.02, "{} {}", sum / n as Flt, area);
+        }
+    }
+
+    // a shape whose solver gives up on some rays
+    struct Nan(Transform, Texture);
+
+    impl Local for Nan {
+        fn transform(&self) -> &Transform {
+            &self.0
+        }
+
+        fn texture(&self) -> &Texture {
+            &self.1
+        }
+
+        fn roots(&self, _o: Vct, _d: Vct) -> [Flt; 4] {
+            [Flt::NAN, 2.0, Flt::NAN, 1.0]
+        }
+
+        fn normal(&self, _p: Vct) -> Vct {
+            Vct::new(0.0, 0.0, 1.0)
+        }
+
+        fn uv(&self, _p: Vct) -> ((Flt, Flt), Vct, Vct) {
+            ((0.0, 0.0), Vct::zero(), Vct::zero())
+        }
+
+        fn point(&self, _u: Flt, _v: Flt) -> (Vct, Vct, Vct) {
+            (Vct::zero(), Vct::zero(), Vct::zero())
+        }
+    }
+
+    #[test]
+    fn nan_roots() {
+        let s = Nan(Transform::new(vec![]), diffuse());
+        let r = Ray::new(Vct::zero(), Vct::new(0.0, 0.0, 1.0));
+        assert_eq!(hit_t(&s, &r).map(|tmp| tmp.0), Some(1.0));
+    }
+}
@@ -1,7 +1,8 @@
 use super::ds::{BSPTree, KDTree, MyTree};
 use crate::{
-    geo::{Geo, HitResult, HitTemp, TextureRaw},
+    geo::{Geo, HitResult, HitTemp, Texture, TextureImage, TextureKind},
     linalg::{Mat, Ray, Transform, Vct},
+    utils::Sampler,
     Deserialize, Flt, Serialize, EPS,
 };
 use serde::de::{self, Deserializer, MapAccess, Visitor};
@@ -29,21 +30,28 @@ pub enum Tree {
 #[derive(Clone, Debug)]
 pub struct Mesh {
     pub path: String,
-    pub texture: TextureRaw,
+    pub texture: Texture, // images, patterns and relief use the uv of the vertices
+    pub mask: Option<TextureImage>, // over the uv too, rays go through where its alpha cuts out
     pub transform: Transform,
     pub pos: Vec<Vct>,
     pub norm: Vec<Vct>,
     pub uv: Vec<(Flt, Flt)>,
     pub tri: Vec<(usize, usize, usize)>,
     pub pre: Vec<Mat>,
+    pub area: Vec<Flt>, // the total area of the triangles up to every one
     pub tree: Tree,
 }
 
 impl Mesh {
     #[cfg_attr(rustfmt, rustfmt_skip)]
-    pub fn new(path: String, texture: TextureRaw, transform: Transform, tree_type: TreeType) -> Self {
+    pub fn new(path: String, texture: Texture, transform: Transform, tree_type: TreeType) -> Self {
         let (pos, norm, uv, tri, pre, tree) = Self::load(&path, &transform, tree_type);
-        Self { path, texture, transform, pos, norm, uv, tri, pre, tree }
+        let mut sum = 0.0;
+        let area = tri.iter().map(|&(a, b, c)| {
+            sum += ((pos[b] - pos[a]) % (pos[c] - pos[a])).len() * 0.5;
+            sum
+        }).collect();
+        Self { path, texture, mask: None, transform, pos, norm, uv, tri, pre, area, tree }
     }
 
     fn load(
@@ -186,9 +194,51 @@ impl Mesh {
             if v < 0.0 || u + v > 1.0 {
                 return;
             }
+            if !self.opaque(i, u, v) {
+                return;
+            }
             *ans = Some((t, Some((i, u, v))));
         }
     }
+
+    // rays go through the holes of the texture and of the mask, at their finest level
+    fn opaque(&self, i: usize, u: Flt, v: Flt) -> bool {
+        match self.uv_at(i, u, v) {
+            Some((x, y)) => {
+                let at = |img: &TextureImage| {
+                    img.lookup(x * img.image.w as Flt, y * img.image.h as Flt, 0.0)
+                };
+                self.texture.opaque(at) && self.mask.as_ref().is_none_or(|m| m.opaque(|| at(m)))
+            },
+            None => true,
+        }
+    }
+
+    // the derivatives of a triangle along the uv of its vertices
+    fn derivatives(&self, i: usize) -> Option<(Vct, Vct)> {
+        let (a, b, c) = self.tri[i];
+        let (ta, tb, tc) = (self.uv[a], self.uv[b], self.uv[c]);
+        let (e1, e2) = (self.pos[b] - self.pos[a], self.pos[c] - self.pos[a]);
+        let (u1, v1, u2, v2) = (tb.0 - ta.0, tb.1 - ta.1, tc.0 - ta.0, tc.1 - ta.1);
+        let det = u1 * v2 - u2 * v1;
+        if det.abs() <= EPS {
+            return None;
+        }
+        Some(((e1 * v2 - e2 * v1) / det, (e2 * u1 - e1 * u2) / det))
+    }
+
+    // the uv at (u, v) of a triangle, if its vertices have them
+    fn uv_at(&self, i: usize, u: Flt, v: Flt) -> Option<(Flt, Flt)> {
+        let (a, b, c) = self.tri[i];
+        let (ta, tb, tc) = (self.uv[a], self.uv[b], self.uv[c]);
+        if ta.0 < 0.0 || tb.0 < 0.0 || tc.0 < 0.0 {
+            return None;
+        }
+        Some((
+            ta.0 * (1.0 - u - v) + tb.0 * u + tc.0 * v,
+            ta.1 * (1.0 - u - v) + tb.1 * u + tc.1 * v,
+        ))
+    }
 }
 
 impl Geo for Mesh {
@@ -203,12 +253,45 @@ impl Geo for Mesh {
     fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
         let (i, u, v) = tmp.1.unwrap();
         let (a, b, c) = self.tri[i];
+        let mut norm = self.norm[a] * (1.0 - u - v) + self.norm[b] * u + self.norm[c] * v;
+        let (uv, deriv) = (self.uv_at(i, u, v), self.derivatives(i));
+        if let (Some(rl), Some(uv), Some((dpdu, dpdv))) = (self.texture.relief(), uv, deriv) {
+            norm = rl.perturb(norm.norm(), uv, dpdu, dpdv);
+        }
+        let pos = r.origin + r.direct * tmp.0;
+        // triangles without uv take the corner of the image or the pattern
+        let (x, y) = uv.unwrap_or((0.0, 0.0));
         HitResult {
-            pos: r.origin + r.direct * tmp.0,
-            norm: self.norm[a] * (1.0 - u - v) + self.norm[b] * u + self.norm[c] * v,
-            texture: self.texture,
+            pos,
+            norm,
+            texture: match self.texture.kind {
+                TextureKind::Raw(ref raw) => *raw,
+                TextureKind::Image(ref img) => {
+                    let (w, h) = (img.image.w as Flt, img.image.h as Flt);
+                    // texels along a unit of length
+                    let scale = deriv.map_or(0.0, |(dpdu, dpdv)| {
+                        (w / dpdu.len().max(EPS)).max(h / dpdv.len().max(EPS))
+                    });
+                    img.texel(img.lookup(x * w, y * h, r.footprint(tmp.0) * scale))
+                },
+                TextureKind::Procedural(ref pr) => pr.texel(self.transform.inv * pos, (x, y)),
+            },
         }
     }
+
+    // a triangle by its area, then a uniform point in it
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        let total = *self.area.last()?;
+        let at = rng.gen() * total;
+        let i = self.area.partition_point(|&a| a <= at).min(self.tri.len() - 1);
+        let su = rng.gen().sqrt();
+        let (u, v) = (rng.gen() * su, 1.0 - su);
+        let (a, b, c) = self.tri[i];
+        let p = self.pos[a] * (1.0 - u - v) + self.pos[b] * u + self.pos[c] * v;
+        let n = ((self.pos[b] - self.pos[a]) % (self.pos[c] - self.pos[a])).norm();
+        let r = Ray::new(p + n, -n);
+        Some((self.hit(&r, (1.0, Some((i, u, v)))), total))
+    }
 }
 
 impl Serialize for Mesh {
@@ -216,9 +299,10 @@ impl Serialize for Mesh {
     where
         S: Serializer,
     {
-        let mut s = serializer.serialize_struct("mesh", 3)?;
+        let mut s = serializer.serialize_struct("mesh", 4)?;
         s.serialize_field("path", &self.path)?;
         s.serialize_field("texture", &self.texture)?;
+        s.serialize_field("mask", &self.mask)?;
         s.serialize_field("transform", &self.transform)?;
         s.end()
     }
@@ -236,6 +320,7 @@ impl<'de> Deserialize<'de> for Mesh {
         enum Field {
             Path,
             Texture,
+            Mask,
             Transform,
             TreeType,
             Type,
@@ -254,6 +339,7 @@ impl<'de> Deserialize<'de> for Mesh {
             {
                 let mut path = None;
                 let mut texture = None;
+                let mut mask = None;
                 let mut transform = None;
                 let mut tree_type = None;
                 while let Some(key) = map.next_key()? {
@@ -270,6 +356,12 @@ impl<'de> Deserialize<'de> for Mesh {
                             }
                             texture = Some(map.next_value()?);
                         }
+                        Field::Mask => {
+                            if mask.is_some() {
+                                return Err(de::Error::duplicate_field("mask"));
+                            }
+                            mask = Some(map.next_value()?);
+                        }
                         Field::Transform => {
                             if transform.is_some() {
                                 return Err(de::Error::duplicate_field("transform"));
@@ -289,7 +381,9 @@ impl<'de> Deserialize<'de> for Mesh {
                 let texture = texture.ok_or_else(|| de::Error::missing_field("texture"))?;
                 let transform = transform.ok_or_else(|| de::Error::missing_field("transform"))?;
                 let tree_type = tree_type.ok_or_else(|| de::Error::missing_field("tree_type"))?;
-                Ok(Mesh::new(path, texture, transform, tree_type))
+                let mut mesh = Mesh::new(path, texture, transform, tree_type);
+                mesh.mask = mask;
+                Ok(mesh)
             }
         }
 
@@ -1,11 +1,24 @@
 pub mod bezier;
+pub mod cone;
+pub mod cuboid;
+pub mod cylinder;
+pub mod disk;
 pub mod ds;
+pub mod local;
 pub mod mesh;
 pub mod plane;
+pub mod rectangle;
 pub mod sphere;
+pub mod torus;
 
 pub use bezier::BezierRotate;
+pub use cone::Cone;
+pub use cuboid::Cuboid;
+pub use cylinder::Cylinder;
+pub use disk::Disk;
 pub use mesh::Mesh;
 pub use plane::Plane;
+pub use rectangle::Rectangle;
 pub use sphere::Sphere;
+pub use torus::Torus;
 
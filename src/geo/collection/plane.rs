@@ -1,7 +1,8 @@
 use crate::{
-    geo::{Geo, HitResult, HitTemp, Texture, TextureRaw},
-    linalg::{Ray, Transform, Vct},
-    Deserialize, Serialize, EPS,
+    geo::{Geo, HitResult, HitTemp, Texture, TextureImage, TextureKind},
+    linalg::{Ray, Transform},
+    utils::image::RGBA,
+    Deserialize, Flt, Serialize, EPS,
 };
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -14,6 +15,15 @@ impl Plane {
     pub fn new(texture: Texture, transform: Transform) -> Self {
         Self { texture, transform }
     }
+
+    // the color of an image texture where r hits at t
+    fn lookup(&self, img: &TextureImage, r: &Ray, t: Flt) -> RGBA {
+        let v = r.origin + r.direct * t - self.transform.pos();
+        let px = self.transform.x().dot(v) * img.width_ratio;
+        let py = self.transform.y().dot(v) * img.height_ratio;
+        let scale = img.width_ratio.abs().max(img.height_ratio.abs());
+        img.lookup(px, py, r.footprint(t) * scale)
+    }
 }
 
 impl Geo for Plane {
@@ -23,7 +33,7 @@ impl Geo for Plane {
         let d = n.dot(r.direct);
         if d.abs() > EPS {
             let t = n.dot(self.transform.pos() - r.origin) / d;
-            if t > EPS {
+            if t > EPS && self.texture.opaque(|img| self.lookup(img, r, t)) {
                 return Some((t, None));
             }
         }
@@ -34,21 +44,28 @@ impl Geo for Plane {
     fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
         let pos = r.origin + r.direct * tmp.0;
         let n = self.transform.z();
+        let mut norm = if n.dot(r.direct) > 0.0 { n } else { -n };
+        if let Some(rl) = self.texture.relief() {
+            let (x, y, v) = (self.transform.x(), self.transform.y(), pos - self.transform.pos());
+            // uv in units of length, or of the size of the image which then lines up with it
+            let (su, sv): (Flt, Flt) = match self.texture.kind {
+                TextureKind::Image(ref img) => {
+                    (img.image.w as Flt / img.width_ratio, img.image.h as Flt / img.height_ratio)
+                },
+                _ => (1.0, 1.0),
+            };
+            norm = rl.perturb(norm, (x.dot(v) / su, y.dot(v) / sv), x * su, y * sv);
+        }
         HitResult {
             pos,
-            norm: if n.dot(r.direct) > 0.0 { n } else { -n },
-            texture: match self.texture {
-                Texture::Raw(ref raw) => *raw,
-                Texture::Image(ref img) => {
+            norm,
+            texture: match self.texture.kind {
+                TextureKind::Raw(ref raw) => *raw,
+                TextureKind::Image(ref img) => img.texel(self.lookup(img, r, tmp.0)),
+                TextureKind::Procedural(ref pr) => {
                     let v = pos - self.transform.pos();
-                    let px = self.transform.x().dot(v) * img.width_ratio;
-                    let py = self.transform.y().dot(v) * img.height_ratio;
-                    let col = img.image.get_repeat(px as isize, py as isize);
-                    TextureRaw {
-                        emission: Vct::zero(),
-                        color: Vct::new(col.0, col.1, col.2),
-                        material: img.material,
-                    }
+                    let uv = (self.transform.x().dot(v), self.transform.y().dot(v));
+                    pr.texel(self.transform.inv * pos, uv)
                 }
             },
         }
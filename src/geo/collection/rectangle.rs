@@ -0,0 +1,72 @@
+use super::local::{self, Local, NONE};
+use crate::{
+    geo::{Geo, HitResult, HitTemp, Texture},
+    linalg::{Ray, Transform, Vct},
+    utils::Sampler,
+    Deserialize, Flt, Serialize, EPS,
+};
+
+// width along x by height along y, centered at the origin and facing z
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rectangle {
+    pub width: Flt,
+    pub height: Flt,
+    pub texture: Texture,
+    pub transform: Transform,
+}
+
+impl Rectangle {
+    pub fn new(width: Flt, height: Flt, texture: Texture, transform: Transform) -> Self {
+        Self { width, height, texture, transform }
+    }
+}
+
+impl Local for Rectangle {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    fn roots(&self, o: Vct, d: Vct) -> [Flt; 4] {
+        if d.z.abs() < EPS {
+            return NONE;
+        }
+        let t = -o.z / d.z;
+        let p = o + d * t;
+        if p.x.abs() > self.width * 0.5 || p.y.abs() > self.height * 0.5 {
+            return NONE;
+        }
+        [t, Flt::INFINITY, Flt::INFINITY, Flt::INFINITY]
+    }
+
+    fn normal(&self, _p: Vct) -> Vct {
+        Vct::new(0.0, 0.0, 1.0)
+    }
+
+    fn uv(&self, p: Vct) -> ((Flt, Flt), Vct, Vct) {
+        let uv = (p.x / self.width + 0.5, p.y / self.height + 0.5);
+        (uv, Vct::new(self.width, 0.0, 0.0), Vct::new(0.0, self.height, 0.0))
+    }
+
+    fn point(&self, u: Flt, v: Flt) -> (Vct, Vct, Vct) {
+        let p = Vct::new((u - 0.5) * self.width, (v - 0.5) * self.height, 0.0);
+        (p, Vct::new(self.width, 0.0, 0.0), Vct::new(0.0, self.height, 0.0))
+    }
+}
+
+impl Geo for Rectangle {
+    fn hit_t(&self, r: &Ray) -> Option<HitTemp> {
+        local::hit_t(self, r)
+    }
+
+    fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
+        local::hit(self, r, tmp)
+    }
+
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        local::sample(self, rng)
+    }
+}
@@ -1,9 +1,13 @@
+use super::local::{self, quadratic, turn, Local, NONE};
 use crate::{
-    geo::{Geo, HitResult, HitTemp, Material, Texture, TextureRaw},
+    geo::{Geo, HitResult, HitTemp, Texture},
     linalg::{Ray, Transform, Vct},
-    Deserialize, Flt, Serialize, EPS,
+    utils::Sampler,
+    Deserialize, Flt, Serialize, EPS, PI,
 };
 
+// Centered at the origin, an ellipsoid under non-uniform scale. Textures wrap around it by
+// longitude in u and latitude in v, with the poles on z and the north one at the top of images.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Sphere {
     pub radius: Flt,
@@ -17,45 +21,63 @@ impl Sphere {
     }
 }
 
+impl Local for Sphere {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    fn roots(&self, o: Vct, d: Vct) -> [Flt; 4] {
+        let mut ret = NONE;
+        if let Some((t0, t1)) =
+            quadratic(d.len2(), 2.0 * o.dot(d), o.len2() - self.radius * self.radius)
+        {
+            ret[0] = t0;
+            ret[1] = t1;
+        }
+        ret
+    }
+
+    fn normal(&self, p: Vct) -> Vct {
+        p
+    }
+
+    fn uv(&self, p: Vct) -> ((Flt, Flt), Vct, Vct) {
+        let u = turn(p.x, p.y);
+        let theta = (p.z / self.radius).clamp(-1.0, 1.0).acos();
+        let (sin, cos) = (2.0 * PI * u).sin_cos();
+        let (st, ct) = theta.sin_cos();
+        let dpdu = Vct::new(-p.y, p.x, 0.0) * (2.0 * PI);
+        let dpdv = Vct::new(-ct * cos, -ct * sin, st) * (PI * self.radius);
+        ((u, 1.0 - theta / PI), dpdu, dpdv)
+    }
+
+    // v goes along z, which spreads the points evenly over the sphere
+    fn point(&self, u: Flt, v: Flt) -> (Vct, Vct, Vct) {
+        let z = 2.0 * v - 1.0;
+        let s = (1.0 - z * z).max(0.0).sqrt();
+        let (sin, cos) = (2.0 * PI * u).sin_cos();
+        let p = Vct::new(s * cos, s * sin, z) * self.radius;
+        let dpdu = Vct::new(-p.y, p.x, 0.0) * (2.0 * PI);
+        let dpdv =
+            Vct::new(-z * cos / s.max(EPS), -z * sin / s.max(EPS), 1.0) * (2.0 * self.radius);
+        (p, dpdu, dpdv)
+    }
+}
+
 impl Geo for Sphere {
     fn hit_t(&self, r: &Ray) -> Option<HitTemp> {
-        let op = self.transform.pos() - r.origin;
-        let b = op.dot(r.direct);
-        let det = b * b - op.len2() + self.radius * self.radius;
-        if det < 0.0 {
-            return None;
-        }
-        let det = det.sqrt();
-        let t = b - det;
-        if t > EPS {
-            return Some((t, None));
-        }
-        let t = b + det;
-        if t > EPS {
-            return Some((t, None));
-        }
-        None
+        local::hit_t(self, r)
     }
 
     fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
-        let pos = r.origin + r.direct * tmp.0;
-        HitResult {
-            pos,
-            norm: (pos - self.transform.pos()).norm(),
-            texture: match self.texture {
-                Texture::Raw(ref raw) => *raw,
-                Texture::Image(ref img) => {
-                    let p = (self.transform.inv * pos).norm();
-                    let px = (p.x * 0.5 + 0.5) * img.image.w as Flt;
-                    let py = (p.y * 0.5 + 0.5) * img.image.h as Flt;
-                    let col = img.image.get_repeat(px as isize, py as isize);
-                    TextureRaw {
-                        emission: Vct::zero(),
-                        color: Vct::new(col.0, col.1, col.2),
-                        material: if col.3 > 0.0 { Material::Diffuse } else { img.material },
-                    }
-                }
-            },
-        }
+        local::hit(self, r, tmp)
+    }
+
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        local::sample(self, rng)
     }
 }
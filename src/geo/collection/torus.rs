@@ -0,0 +1,181 @@
+use super::local::{self, quadratic, turn, Local, NONE};
+use crate::{
+    geo::{Geo, HitResult, HitTemp, Texture},
+    linalg::{Ray, Transform, Vct},
+    utils::Sampler,
+    Deserialize, Flt, Serialize, EPS, PI,
+};
+
+// Around z in the xy plane, the tube of minor radius goes around the circle of major radius.
+// u goes around z and v around the tube.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Torus {
+    pub major: Flt,
+    pub minor: Flt,
+    pub texture: Texture,
+    pub transform: Transform,
+}
+
+// the largest real root of x^3 + a x^2 + b x + c
+fn cubic(a: Flt, b: Flt, c: Flt) -> Flt {
+    let (p, q) = (b - a * a / 3.0, 2.0 * a * a * a / 27.0 - a * b / 3.0 + c);
+    let det = q * q / 4.0 + p * p * p / 27.0;
+    let x = if det > 0.0 {
+        let s = det.sqrt();
+        (-q / 2.0 + s).cbrt() + (-q / 2.0 - s).cbrt()
+    } else {
+        let m = (-p / 3.0).max(0.0).sqrt();
+        let cos = if m > 0.0 { (-q / (2.0 * m * m * m)).clamp(-1.0, 1.0) } else { 0.0 };
+        2.0 * m * (cos.acos() / 3.0).cos()
+    } - a / 3.0;
+    // polish it
+    (0..2).fold(x, |x, _| {
+        let (f, df) = (((x + a) * x + b) * x + c, (3.0 * x + 2.0 * a) * x + b);
+        if df.abs() > EPS {
+            x - f / df
+        } else {
+            x
+        }
+    })
+}
+
+// the real roots of x^4 + a x^3 + b x^2 + c x + d by the method of Ferrari
+fn quartic(a: Flt, b: Flt, c: Flt, d: Flt) -> [Flt; 4] {
+    // y^4 + p y^2 + q y + r with x = y - a / 4
+    let (a2, s) = (a * a, a / 4.0);
+    let p = b - 3.0 * a2 / 8.0;
+    let q = c - a * b / 2.0 + a2 * a / 8.0;
+    let r = d - a * c / 4.0 + a2 * b / 16.0 - 3.0 * a2 * a2 / 256.0;
+    let mut ret = NONE;
+    let mut push = |i: usize, x: Option<(Flt, Flt)>| {
+        if let Some((x0, x1)) = x {
+            ret[i] = x0 - s;
+            ret[i + 1] = x1 - s;
+        }
+    };
+    if q.abs() < EPS * EPS {
+        // in y^2
+        if let Some((z0, z1)) = quadratic(1.0, p, r) {
+            let root = |z: Flt| if z >= 0.0 { Some((-z.sqrt(), z.sqrt())) } else { None };
+            push(0, root(z0));
+            push(2, if z1 < Flt::INFINITY { root(z1) } else { None });
+        }
+    } else {
+        // the square (y^2 + p / 2 + m)^2 leaves a square in y with m from the resolvent
+        let m = cubic(p, p * p / 4.0 - r, -q * q / 8.0).max(EPS * EPS);
+        let (k, l) = ((2.0 * m).sqrt(), q / (2.0 * (2.0 * m).sqrt()));
+        push(0, quadratic(1.0, -k, p / 2.0 + m + l));
+        push(2, quadratic(1.0, k, p / 2.0 + m - l));
+    }
+    // polish them
+    for x in ret.iter_mut().filter(|x| x.is_finite()) {
+        for _ in 0..2 {
+            let f = (((*x + a) * *x + b) * *x + c) * *x + d;
+            let df = ((4.0 * *x + 3.0 * a) * *x + 2.0 * b) * *x + c;
+            if df.abs() > EPS {
+                *x -= f / df;
+            }
+        }
+    }
+    ret
+}
+
+impl Torus {
+    pub fn new(major: Flt, minor: Flt, texture: Texture, transform: Transform) -> Self {
+        Self { major, minor, texture, transform }
+    }
+}
+
+impl Local for Torus {
+    fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + y^2), from near the torus along a unit direction
+    // to keep the quartic well conditioned
+    fn roots(&self, o: Vct, d: Vct) -> [Flt; 4] {
+        let (big, small) = (self.major, self.minor);
+        let bound = big + small;
+        let len = d.len();
+        let dn = d / len;
+        let b = o.dot(dn);
+        if o.len2() - b * b > bound * bound {
+            return NONE;
+        }
+        // start from the nearest point of the line to the center
+        let o = o - dn * b;
+        let (r2, k) = (big * big, 2.0 * o.dot(dn));
+        let g = o.len2() + r2 - small * small;
+        let (pa, pb) = (dn.x * dn.x + dn.y * dn.y, 2.0 * (o.x * dn.x + o.y * dn.y));
+        let pc = o.x * o.x + o.y * o.y;
+        let mut ret = quartic(
+            2.0 * k,
+            k * k + 2.0 * g - 4.0 * r2 * pa,
+            2.0 * k * g - 4.0 * r2 * pb,
+            g * g - 4.0 * r2 * pc,
+        );
+        ret.iter_mut().filter(|t| t.is_finite()).for_each(|t| *t = (*t - b) / len);
+        ret
+    }
+
+    fn normal(&self, p: Vct) -> Vct {
+        let rho = (p.x * p.x + p.y * p.y).sqrt().max(EPS);
+        p - Vct::new(p.x, p.y, 0.0) * (self.major / rho)
+    }
+
+    fn uv(&self, p: Vct) -> ((Flt, Flt), Vct, Vct) {
+        let rho = (p.x * p.x + p.y * p.y).sqrt();
+        let (u, v) = (turn(p.x, p.y), turn(rho - self.major, p.z));
+        let (sp, cp) = (2.0 * PI * u).sin_cos();
+        let (st, ct) = (2.0 * PI * v).sin_cos();
+        let dpdu = Vct::new(-p.y, p.x, 0.0) * (2.0 * PI);
+        let dpdv = Vct::new(-st * cp, -st * sp, ct) * (2.0 * PI * self.minor);
+        ((u, v), dpdu, dpdv)
+    }
+
+    fn point(&self, u: Flt, v: Flt) -> (Vct, Vct, Vct) {
+        let (sp, cp) = (2.0 * PI * u).sin_cos();
+        let (st, ct) = (2.0 * PI * v).sin_cos();
+        let rho = self.major + self.minor * ct;
+        let p = Vct::new(rho * cp, rho * sp, self.minor * st);
+        let dpdu = Vct::new(-rho * sp, rho * cp, 0.0) * (2.0 * PI);
+        let dpdv = Vct::new(-st * cp, -st * sp, ct) * (2.0 * PI * self.minor);
+        (p, dpdu, dpdv)
+    }
+}
+
+impl Geo for Torus {
+    fn hit_t(&self, r: &Ray) -> Option<HitTemp> {
+        local::hit_t(self, r)
+    }
+
+    fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult {
+        local::hit(self, r, tmp)
+    }
+
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        local::sample(self, rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roots() {
+        // (x - 1)(x - 2)(x + 3)(x - 4) and (x^2 + 1)(x - 1)(x + 1)
+        let mut r = quartic(-4.0, -7.0, 34.0, -24.0);
+        r.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (x, y) in r.iter().zip([-3.0, 1.0, 2.0, 4.0].iter()) {
+            assert!((x - y).abs() < 1e-9);
+        }
+        let mut r = quartic(0.0, 0.0, 0.0, -1.0);
+        r.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!((r[0] + 1.0).abs() < 1e-9 && (r[1] - 1.0).abs() < 1e-9 && r[2].is_infinite());
+    }
+}
@@ -1,10 +1,18 @@
 pub mod collection;
+pub mod procedural;
+pub mod relief;
 pub mod texture;
 
-pub use texture::{Material, Texture, TextureImage, TextureRaw};
+pub use procedural::{Pattern, Stop, TextureProcedural};
+pub use relief::{Relief, ReliefKind};
+pub use texture::{
+    Alpha, Coat, Dispersion, Layer, Material, Mix, Texture, TextureFilter, TextureImage,
+    TextureKind, TextureRaw, Wrap,
+};
 
 use crate::{
     linalg::{Ray, Vct},
+    utils::Sampler,
     Flt,
 };
 
@@ -20,4 +28,10 @@ pub type HitTemp = (Flt, Option<(usize, Flt, Flt)>);
 pub trait Geo: Send + Sync {
     fn hit_t(&self, r: &Ray) -> Option<HitTemp>;
     fn hit(&self, r: &Ray, tmp: HitTemp) -> HitResult;
+
+    // A point on the surface as hit along its normal, and the inverse of its pdf by area. Objects
+    // which can't be sampled, like infinite planes, give None.
+    fn sample(&self, _rng: &mut dyn Sampler) -> Option<(HitResult, Flt)> {
+        None
+    }
 }
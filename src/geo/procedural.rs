@@ -0,0 +1,206 @@
+use super::TextureRaw;
+use crate::{linalg::Vct, utils::Rng, Deserialize, Flt, Serialize, PI};
+use std::sync::OnceLock;
+
+fn default_octaves() -> usize {
+    6
+}
+
+fn default_turbulence() -> Flt {
+    5.0
+}
+
+fn default_rings() -> Flt {
+    1.0
+}
+
+// Scalar patterns in [0, 1] which the ramp turns into colors. Noises are Perlin's improved
+// noise summed over octaves of doubling frequency and halving amplitude.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Pattern {
+    Checker,
+    Noise,
+    Fbm {
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+    },
+    Turbulence {
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+    },
+    Marble {
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default = "default_turbulence")]
+        turbulence: Flt, // how much the veins are distorted
+    },
+    Wood {
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default = "default_turbulence")]
+        turbulence: Flt,
+        #[serde(default = "default_rings")]
+        rings: Flt, // per unit of distance from the y axis
+    },
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Stop {
+    pub at: Flt,
+    pub color: Vct,
+}
+
+fn default_ramp() -> Vec<Stop> {
+    vec![Stop { at: 0.0, color: Vct::zero() }, Stop { at: 1.0, color: Vct::one() }]
+}
+
+fn default_scale() -> Flt {
+    1.0
+}
+
+// A pattern colors the base texture, it is evaluated at the hit position in object space, or
+// at (u, v, 0) with uv. Scale is the size of the features.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextureProcedural {
+    pub pattern: Pattern,
+    #[serde(default = "default_ramp")]
+    pub ramp: Vec<Stop>, // sorted by at, linearly interpolated
+    #[serde(default = "default_scale")]
+    pub scale: Flt,
+    #[serde(default)]
+    pub uv: bool,
+    pub base: TextureRaw,
+}
+
+impl TextureProcedural {
+    pub fn texel(&self, pos: Vct, uv: (Flt, Flt)) -> TextureRaw {
+        let p = if self.uv { Vct::new(uv.0, uv.1, 0.0) } else { pos };
+        let mut t = self.base;
+        t.color = self.ramp(self.pattern.value(p / self.scale));
+        t
+    }
+
+    fn ramp(&self, v: Flt) -> Vct {
+        let r = &self.ramp;
+        match r.iter().position(|s| s.at > v) {
+            Some(0) => r[0].color,
+            Some(i) => {
+                let (a, b) = (r[i - 1], r[i]);
+                a.color + (b.color - a.color) * ((v - a.at) / (b.at - a.at))
+            },
+            None => r.last().map_or(Vct::zero(), |s| s.color),
+        }
+    }
+}
+
+impl Pattern {
+    pub fn value(&self, p: Vct) -> Flt {
+        match *self {
+            Pattern::Checker => {
+                let s = p.x.floor() + p.y.floor() + p.z.floor();
+                if s.rem_euclid(2.0) < 1.0 {
+                    0.0
+                } else {
+                    1.0
+                }
+            },
+            Pattern::Noise => noise(p) * 0.5 + 0.5,
+            Pattern::Fbm { octaves } => (fbm(p, octaves) * 0.5 + 0.5).clamp(0.0, 1.0),
+            Pattern::Turbulence { octaves } => turbulence(p, octaves).min(1.0),
+            Pattern::Marble { octaves, turbulence: t } => {
+                0.5 + 0.5 * (p.x * PI + t * turbulence(p, octaves)).sin()
+            },
+            Pattern::Wood { octaves, turbulence: t, rings } => {
+                let d = (p.x * p.x + p.z * p.z).sqrt();
+                ((d + 0.1 * t * turbulence(p, octaves)) * rings).fract()
+            },
+        }
+    }
+}
+
+fn perm() -> &'static [usize; 512] {
+    static PERM: OnceLock<[usize; 512]> = OnceLock::new();
+    PERM.get_or_init(|| {
+        let mut p: Vec<usize> = (0..256).collect();
+        let mut rng = Rng::new(1);
+        for i in (1..256).rev() {
+            let j = ((rng.gen() * (i + 1) as Flt) as usize).min(i);
+            p.swap(i, j);
+        }
+        let mut perm = [0; 512];
+        (0..512).for_each(|i| perm[i] = p[i & 255]);
+        perm
+    })
+}
+
+// Perlin's improved noise, in [-1, 1]
+pub fn noise(p: Vct) -> Flt {
+    let perm = perm();
+    let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (xi, yi, zi) =
+        ((fx as i64 & 255) as usize, (fy as i64 & 255) as usize, (fz as i64 & 255) as usize);
+    let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+    let fade = |t: Flt| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    let grad = |h: usize, x: Flt, y: Flt, z: Flt| {
+        let h = h & 15;
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 {
+            y
+        } else if h == 12 || h == 14 {
+            x
+        } else {
+            z
+        };
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    };
+    let lerp = |t: Flt, a: Flt, b: Flt| a + t * (b - a);
+    let (a, b) = (perm[xi] + yi, perm[xi + 1] + yi);
+    let (aa, ab, ba, bb) = (perm[a] + zi, perm[a + 1] + zi, perm[b] + zi, perm[b + 1] + zi);
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, grad(perm[aa], x, y, z), grad(perm[ba], x - 1.0, y, z)),
+            lerp(u, grad(perm[ab], x, y - 1.0, z), grad(perm[bb], x - 1.0, y - 1.0, z)),
+        ),
+        lerp(
+            v,
+            lerp(u, grad(perm[aa + 1], x, y, z - 1.0), grad(perm[ba + 1], x - 1.0, y, z - 1.0)),
+            lerp(
+                u,
+                grad(perm[ab + 1], x, y - 1.0, z - 1.0),
+                grad(perm[bb + 1], x - 1.0, y - 1.0, z - 1.0),
+            ),
+        ),
+    )
+}
+
+pub fn fbm(p: Vct, octaves: usize) -> Flt {
+    (0..octaves).map(|i| noise(p * (1 << i) as Flt) / (1 << i) as Flt).sum()
+}
+
+pub fn turbulence(p: Vct, octaves: usize) -> Flt {
+    (0..octaves).map(|i| noise(p * (1 << i) as Flt).abs() / (1 << i) as Flt).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        // noise vanishes on the lattice and stays in range
+        assert_eq!(noise(Vct::new(3.0, -2.0, 7.0)), 0.0);
+        let mut rng = Rng::new(9);
+        for _ in 0..1000 {
+            let p = Vct::new(rng.gen(), rng.gen(), rng.gen()) * 100.0 - Vct::one() * 50.0;
+            assert!(noise(p).abs() <= 1.0);
+            let v = Pattern::Wood { octaves: 4, turbulence: 5.0, rings: 1.0 }.value(p);
+            assert!((0.0..1.0).contains(&v));
+        }
+        let checker = |x: Flt| Pattern::Checker.value(Vct::new(x, 0.5, 0.5));
+        assert_eq!((checker(0.5), checker(1.5), checker(-0.5)), (0.0, 1.0, 1.0));
+    }
+}
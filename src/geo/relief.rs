@@ -0,0 +1,208 @@
+use super::TextureImage;
+use crate::{
+    linalg::Vct,
+    utils::{image::RGBA, Image},
+    Deserialize, Flt, Serialize, EPS,
+};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeStruct, Serializer};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReliefKind {
+    Normal, // tangent space, x along u, y along v and z out of the surface
+    Bump,   // grayscale heights
+}
+
+// A map perturbing the normal of a surface, it covers [0, 1) of the uv of the geometry scaled
+// by scale and repeats. Strength is the height of white for bump maps and scales the slope of
+// normal maps.
+#[derive(Clone, Debug)]
+pub struct Relief {
+    pub kind: ReliefKind,
+    pub path: String,
+    pub strength: Flt,
+    pub scale: Flt,
+    pub image: Image,
+}
+
+impl Relief {
+    pub fn new(kind: ReliefKind, path: String, strength: Flt, scale: Flt) -> Self {
+        let image = TextureImage::load(&path);
+        Self { kind, path, strength, scale, image }
+    }
+
+    // bilinear, texel centers are at half integers
+    fn at(&self, u: Flt, v: Flt) -> RGBA {
+        let (x, y) = (u * self.image.w as Flt - 0.5, v * self.image.h as Flt - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let mut c = (0.0, 0.0, 0.0, 0.0);
+        for &(dx, dy, w) in [
+            (0, 0, (1.0 - fx) * (1.0 - fy)),
+            (1, 0, fx * (1.0 - fy)),
+            (0, 1, (1.0 - fx) * fy),
+            (1, 1, fx * fy),
+        ]
+        .iter()
+        {
+            let t = self.image.get_repeat(x0 + dx, y0 + dy);
+            c = (c.0 + t.0 * w, c.1 + t.1 * w, c.2 + t.2 * w, c.3 + t.3 * w);
+        }
+        c
+    }
+
+    fn height(&self, u: Flt, v: Flt) -> Flt {
+        let c = self.at(u, v);
+        (c.0 + c.1 + c.2) / 3.0 * self.strength
+    }
+
+    // The normal at uv, dpdu and dpdv are the derivatives of the position along the uv of the
+    // geometry. The result stays on the side of norm.
+    pub fn perturb(&self, norm: Vct, uv: (Flt, Flt), dpdu: Vct, dpdv: Vct) -> Vct {
+        let (u, v) = (uv.0 / self.scale, uv.1 / self.scale);
+        let (dpdu, dpdv) = (dpdu * self.scale, dpdv * self.scale);
+        // the tangent frame, made orthonormal around norm
+        let t = dpdu - norm * norm.dot(dpdu);
+        if t.len2() < EPS * EPS {
+            return norm;
+        }
+        let t = t.norm();
+        let b = if (norm % t).dot(dpdv) < 0.0 { t % norm } else { norm % t };
+        let n = match self.kind {
+            ReliefKind::Normal => {
+                let c = self.at(u, v);
+                let (x, y, z) = (c.0 * 2.0 - 1.0, c.1 * 2.0 - 1.0, c.2 * 2.0 - 1.0);
+                t * (x * self.strength) + b * (y * self.strength) + norm * z.max(EPS)
+            },
+            ReliefKind::Bump => {
+                // slopes along the tangents by differences of a texel
+                let (du, dv) = (1.0 / self.image.w as Flt, 1.0 / self.image.h as Flt);
+                let h = self.height(u, v);
+                let su = (self.height(u + du, v) - h) / (du * dpdu.dot(t).abs().max(EPS));
+                let sv = (self.height(u, v + dv) - h) / (dv * dpdv.dot(b).abs().max(EPS));
+                norm - t * su - b * sv
+            },
+        };
+        n.norm()
+    }
+}
+
+impl Serialize for Relief {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("relief", 4)?;
+        s.serialize_field("type", &self.kind)?;
+        s.serialize_field("path", &self.path)?;
+        s.serialize_field("strength", &self.strength)?;
+        s.serialize_field("scale", &self.scale)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Relief {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ReliefVisitor;
+
+        #[derive(Deserialize)]
+        #[serde(field_identifier, rename_all = "snake_case")]
+        enum Field {
+            Type,
+            Path,
+            Strength,
+            Scale,
+        }
+
+        impl<'de> Visitor<'de> for ReliefVisitor {
+            type Value = Relief;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("Relief")
+            }
+
+            fn visit_map<V>(self, mut map: V) -> Result<Relief, V::Error>
+            where
+                V: MapAccess<'de>,
+            {
+                let mut kind = None;
+                let mut path = None;
+                let mut strength = None;
+                let mut scale = None;
+                while let Some(key) = map.next_key()? {
+                    match key {
+                        Field::Type => {
+                            if kind.is_some() {
+                                return Err(de::Error::duplicate_field("type"));
+                            }
+                            kind = Some(map.next_value()?);
+                        }
+                        Field::Path => {
+                            if path.is_some() {
+                                return Err(de::Error::duplicate_field("path"));
+                            }
+                            path = Some(map.next_value()?);
+                        }
+                        Field::Strength => {
+                            if strength.is_some() {
+                                return Err(de::Error::duplicate_field("strength"));
+                            }
+                            strength = Some(map.next_value()?);
+                        }
+                        Field::Scale => {
+                            if scale.is_some() {
+                                return Err(de::Error::duplicate_field("scale"));
+                            }
+                            scale = Some(map.next_value()?);
+                        }
+                    }
+                }
+                let kind = kind.ok_or_else(|| de::Error::missing_field("type"))?;
+                let path = path.ok_or_else(|| de::Error::missing_field("path"))?;
+                Ok(Relief::new(kind, path, strength.unwrap_or(1.0), scale.unwrap_or(1.0)))
+            }
+        }
+
+        deserializer.deserialize_map(ReliefVisitor {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relief(kind: ReliefKind, c: Vec<RGBA>) -> Relief {
+        let image = Image { w: c.len(), h: 1, c };
+        Relief { kind, path: String::new(), strength: 1.0, scale: 1.0, image }
+    }
+
+    #[test]
+    fn perturb() {
+        let (n, du, dv) =
+            (Vct::new(0.0, 0.0, 1.0), Vct::new(1.0, 0.0, 0.0), Vct::new(0.0, 1.0, 0.0));
+        // a flat normal map and a flat bump map keep the normal
+        let flat = relief(ReliefKind::Normal, vec![(0.5, 0.5, 1.0, 0.0)]);
+        assert!((flat.perturb(n, (0.3, 0.7), du, dv) - n).len() < 1e-6);
+        let flat = relief(ReliefKind::Bump, vec![(0.2, 0.2, 0.2, 0.0)]);
+        assert!((flat.perturb(n, (0.3, 0.7), du, dv) - n).len() < 1e-6);
+        // a normal map leaning along u, from either side of the surface
+        let lean = relief(ReliefKind::Normal, vec![(1.0, 0.5, 0.5, 0.0)]);
+        let p = lean.perturb(n, (0.5, 0.5), du, dv);
+        assert!(p.x > 0.7 && p.z > 0.0);
+        let p = lean.perturb(-n, (0.5, 0.5), du, dv);
+        assert!(p.x > 0.7 && p.z < 0.0);
+        // heights rising along u tilt the normal back
+        let ramp = relief(
+            ReliefKind::Bump,
+            (0..4).map(|i| (i as Flt / 3.0, i as Flt / 3.0, i as Flt / 3.0, 0.0)).collect(),
+        );
+        let p = ramp.perturb(n, (0.25, 0.5), du, dv);
+        assert!(p.x < -0.5 && p.z > 0.0);
+    }
+}
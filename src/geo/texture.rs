@@ -1,4 +1,9 @@
-use crate::{linalg::Vct, utils::Image, Deserialize, Flt, Serialize};
+use super::{Relief, TextureProcedural};
+use crate::{
+    linalg::Vct,
+    utils::{image::RGBA, Image},
+    Deserialize, Flt, Serialize,
+};
 use serde::de::{self, Deserializer, MapAccess, Visitor};
 use serde::ser::{SerializeStruct, Serializer};
 use std::fmt;
@@ -9,6 +14,87 @@ pub enum Material {
     Diffuse,
     Specular,
     Refractive,
+    Null, // only the boundary of a medium, rays go straight through
+    Subsurface,
+    Mix,    // one of the two materials of mix, picked at random
+    Coated, // a clear dielectric coat over the base of coat
+}
+
+// a material inside a mix or under a coat, with the color of the texture when it has none
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Layer {
+    pub material: Material,
+    #[serde(default)]
+    pub color: Option<Vct>,
+}
+
+// the layers of a mix are plain materials, and a coat doesn't go over another coat
+fn plain<'de, D>(d: D, refused: &[Material], of: &str) -> Result<Layer, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let layer = Layer::deserialize(d)?;
+    match refused.iter().find(|&&m| m == layer.material) {
+        Some(m) => Err(de::Error::custom(format!("{:?} can't be the layer of {}", m, of))),
+        None => Ok(layer),
+    }
+}
+
+fn mix_layer<'de, D: Deserializer<'de>>(d: D) -> Result<Layer, D::Error> {
+    plain(d, &[Material::Mix, Material::Coated], "a mix")
+}
+
+fn coat_base<'de, D: Deserializer<'de>>(d: D) -> Result<Layer, D::Error> {
+    plain(d, &[Material::Coated], "a coat")
+}
+
+// Second is picked with probability weight, or the luminance of the texture color when the
+// weight is missing so that an image can drive the mix.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Mix {
+    #[serde(deserialize_with = "mix_layer")]
+    pub first: Layer,
+    #[serde(deserialize_with = "mix_layer")]
+    pub second: Layer,
+    #[serde(default)]
+    pub weight: Option<Flt>,
+}
+
+fn default_coat_ior() -> Flt {
+    1.5
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Coat {
+    #[serde(deserialize_with = "coat_base")]
+    pub base: Layer, // may be a mix
+    #[serde(default = "default_coat_ior")]
+    pub ior: Flt,
+}
+
+// The index of refraction of a refractive material depending on the wavelength in micrometers,
+// Cauchy's n = a + b / l^2 or Sellmeier's n^2 = 1 + sum of b_i l^2 / (l^2 - c_i).
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Dispersion {
+    Cauchy { a: Flt, b: Flt },
+    Sellmeier { b: [Flt; 3], c: [Flt; 3] },
+}
+
+impl Dispersion {
+    // the wavelength of the sodium D line, used outside the spectral mode
+    pub const D_LINE: Flt = 589.3;
+
+    // the index at lambda in nanometers
+    pub fn ior(&self, lambda: Flt) -> Flt {
+        let l2 = (lambda / 1000.0).powi(2);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<Flt>()).sqrt()
+            },
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -16,6 +102,47 @@ pub struct TextureRaw {
     pub emission: Vct,
     pub color: Vct,
     pub material: Material,
+    #[serde(default)]
+    pub absorption: Vct, // per unit length traveled inside a refractive object
+    #[serde(default)]
+    pub mean_free_path: Vct, // of subsurface materials, whose albedo is the color
+    #[serde(default)]
+    pub dispersion: Option<Dispersion>, // replaces the IOR of the scene for refractive objects
+    #[serde(default)]
+    pub mix: Option<Mix>,
+    #[serde(default)]
+    pub coat: Option<Coat>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureFilter {
+    #[default]
+    Nearest,
+    Bilinear,
+    Trilinear, // between the two mipmap levels matching the footprint of the ray
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Wrap {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Alpha {
+    #[default]
+    Cutout, // texels below alpha_threshold are holes which rays go through
+    Opaque,
+    Diffuse, // texels with any alpha are diffuse, the others have the material of the texture
+}
+
+fn default_alpha_threshold() -> Flt {
+    0.5
 }
 
 #[derive(Clone, Debug)]
@@ -24,19 +151,68 @@ pub struct TextureImage {
     pub material: Material,
     pub width_ratio: Flt,
     pub height_ratio: Flt,
+    pub emission_scale: Flt, // the color times it is emitted, zero for no emission
+    pub absorption: Vct,
+    pub mean_free_path: Vct,
+    pub dispersion: Option<Dispersion>,
+    pub mix: Option<Mix>,
+    pub coat: Option<Coat>,
+    pub filter: TextureFilter,
+    pub wrap: Wrap,
+    pub alpha: Alpha,
+    pub alpha_threshold: Flt,
     pub image: Image,
+    pub mipmap: Vec<Image>, // the levels after image, halving the size each
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
-pub enum Texture {
+pub enum TextureKind {
     Raw(TextureRaw),
     Image(TextureImage),
+    Procedural(TextureProcedural),
+}
+
+// The relief sits next to the type in the same object of the scene, over the uv of the shape
+// whatever the kind of the texture.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Texture {
+    #[serde(flatten)]
+    pub kind: TextureKind,
+    #[serde(default)]
+    pub relief: Option<Relief>,
+}
+
+impl Texture {
+    pub fn new(kind: TextureKind) -> Self {
+        Self { kind, relief: None }
+    }
+
+    pub fn relief(&self) -> Option<&Relief> {
+        self.relief.as_ref()
+    }
+
+    // whether rays stop where lookup gives the color of the image, see TextureImage::opaque
+    pub fn opaque(&self, lookup: impl FnOnce(&TextureImage) -> RGBA) -> bool {
+        match self.kind {
+            TextureKind::Image(ref img) => img.opaque(|| lookup(img)),
+            _ => true,
+        }
+    }
 }
 
 impl TextureRaw {
     pub fn new(emission: Vct, color: Vct, material: Material) -> Self {
-        Self { emission, color, material }
+        Self {
+            emission,
+            color,
+            material,
+            absorption: Vct::zero(),
+            mean_free_path: Vct::zero(),
+            dispersion: None,
+            mix: None,
+            coat: None,
+        }
     }
 }
 
@@ -51,6 +227,130 @@ impl TextureImage {
             image,
             width_ratio: 1.0 / width_ratio,
             height_ratio: 1.0 / height_ratio,
+            emission_scale: 0.0,
+            absorption: Vct::zero(),
+            mean_free_path: Vct::zero(),
+            dispersion: None,
+            mix: None,
+            coat: None,
+            filter: TextureFilter::default(),
+            wrap: Wrap::default(),
+            alpha: Alpha::default(),
+            alpha_threshold: default_alpha_threshold(),
+            mipmap: Vec::new(),
+        }
+    }
+
+    pub fn build_mipmap(&mut self) {
+        self.mipmap.clear();
+        let mut last = &self.image;
+        while last.w > 1 || last.h > 1 {
+            let (w, h) = (last.w.div_ceil(2), last.h.div_ceil(2));
+            let mut level = Image::new(w, h);
+            for y in 0..h {
+                for x in 0..w {
+                    let mut c = (0.0, 0.0, 0.0, 0.0);
+                    for &(dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                        let (xx, yy) = ((x * 2 + dx).min(last.w - 1), (y * 2 + dy).min(last.h - 1));
+                        let t = last.get(xx, yy);
+                        c = (c.0 + t.0, c.1 + t.1, c.2 + t.2, c.3 + t.3);
+                    }
+                    level.c[y * w + x] = (c.0 * 0.25, c.1 * 0.25, c.2 * 0.25, c.3 * 0.25);
+                }
+            }
+            self.mipmap.push(level);
+            last = self.mipmap.last().unwrap();
+        }
+    }
+
+    fn fetch(&self, img: &Image, x: isize, y: isize) -> RGBA {
+        let wrap = |v: isize, n: usize| {
+            let n = n as isize;
+            (match self.wrap {
+                Wrap::Repeat => v.rem_euclid(n),
+                Wrap::Clamp => v.max(0).min(n - 1),
+                Wrap::Mirror => {
+                    let v = v.rem_euclid(2 * n);
+                    if v < n {
+                        v
+                    } else {
+                        2 * n - 1 - v
+                    }
+                },
+            }) as usize
+        };
+        img.get(wrap(x, img.w), wrap(y, img.h))
+    }
+
+    // texel centers are at half integers
+    fn bilinear(&self, img: &Image, x: Flt, y: Flt) -> RGBA {
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let mut c = (0.0, 0.0, 0.0, 0.0);
+        for &(dx, dy, w) in [
+            (0, 0, (1.0 - fx) * (1.0 - fy)),
+            (1, 0, fx * (1.0 - fy)),
+            (0, 1, (1.0 - fx) * fy),
+            (1, 1, fx * fy),
+        ]
+        .iter()
+        {
+            let t = self.fetch(img, x0 + dx, y0 + dy);
+            c = (c.0 + t.0 * w, c.1 + t.1 * w, c.2 + t.2 * w, c.3 + t.3 * w);
+        }
+        c
+    }
+
+    // the color at (x, y) in texels, footprint is the width in texels seen by the ray
+    pub fn lookup(&self, x: Flt, y: Flt, footprint: Flt) -> RGBA {
+        match self.filter {
+            TextureFilter::Nearest => {
+                self.fetch(&self.image, x.floor() as isize, y.floor() as isize)
+            },
+            TextureFilter::Bilinear => self.bilinear(&self.image, x, y),
+            TextureFilter::Trilinear => {
+                let l = footprint.max(1.0).log2().min(self.mipmap.len() as Flt);
+                let at = |i: usize| {
+                    let img = if i == 0 { &self.image } else { &self.mipmap[i - 1] };
+                    let (sx, sy) =
+                        (img.w as Flt / self.image.w as Flt, img.h as Flt / self.image.h as Flt);
+                    self.bilinear(img, x * sx, y * sy)
+                };
+                let (i, f) = (l.floor() as usize, l.fract());
+                let a = at(i);
+                if f == 0.0 {
+                    return a;
+                }
+                let b = at(i + 1);
+                let lerp = |a: Flt, b: Flt| a + (b - a) * f;
+                (lerp(a.0, b.0), lerp(a.1, b.1), lerp(a.2, b.2), lerp(a.3, b.3))
+            },
+        }
+    }
+
+    // whether rays stop at the texel given by lookup, rays go through the holes of cutout images
+    // and the texel is not looked up when nothing can be cut out
+    pub fn opaque(&self, lookup: impl FnOnce() -> RGBA) -> bool {
+        self.alpha != Alpha::Cutout || lookup().3 >= self.alpha_threshold
+    }
+
+    // the texture at a texel of the image
+    pub fn texel(&self, col: RGBA) -> TextureRaw {
+        let material = match self.alpha {
+            Alpha::Diffuse if col.3 > 0.0 => Material::Diffuse,
+            _ => self.material,
+        };
+        TextureRaw {
+            emission: Vct::new(col.0, col.1, col.2) * self.emission_scale,
+            color: Vct::new(col.0, col.1, col.2),
+            material,
+            absorption: self.absorption,
+            mean_free_path: self.mean_free_path,
+            dispersion: self.dispersion,
+            mix: self.mix,
+            coat: self.coat,
         }
     }
 
@@ -75,11 +375,21 @@ impl Serialize for TextureImage {
     where
         S: Serializer,
     {
-        let mut s = serializer.serialize_struct("texture_image", 4)?;
+        let mut s = serializer.serialize_struct("texture_image", 14)?;
         s.serialize_field("path", &self.path)?;
         s.serialize_field("material", &self.material)?;
         s.serialize_field("width_ratio", &self.width_ratio)?;
         s.serialize_field("height_ratio", &self.height_ratio)?;
+        s.serialize_field("emission_scale", &self.emission_scale)?;
+        s.serialize_field("absorption", &self.absorption)?;
+        s.serialize_field("mean_free_path", &self.mean_free_path)?;
+        s.serialize_field("dispersion", &self.dispersion)?;
+        s.serialize_field("mix", &self.mix)?;
+        s.serialize_field("coat", &self.coat)?;
+        s.serialize_field("filter", &self.filter)?;
+        s.serialize_field("wrap", &self.wrap)?;
+        s.serialize_field("alpha", &self.alpha)?;
+        s.serialize_field("alpha_threshold", &self.alpha_threshold)?;
         s.end()
     }
 }
@@ -98,6 +408,16 @@ impl<'de> Deserialize<'de> for TextureImage {
             Material,
             WidthRatio,
             HeightRatio,
+            EmissionScale,
+            Absorption,
+            MeanFreePath,
+            Dispersion,
+            Mix,
+            Coat,
+            Filter,
+            Wrap,
+            Alpha,
+            AlphaThreshold,
         }
 
         impl<'de> Visitor<'de> for TextureImageVisitor {
@@ -115,6 +435,16 @@ impl<'de> Deserialize<'de> for TextureImage {
                 let mut material = None;
                 let mut width_ratio = None;
                 let mut height_ratio = None;
+                let mut emission_scale = None;
+                let mut absorption = None;
+                let mut mean_free_path = None;
+                let mut dispersion = None;
+                let mut mix = None;
+                let mut coat = None;
+                let mut filter = None;
+                let mut wrap = None;
+                let mut alpha = None;
+                let mut alpha_threshold = None;
                 while let Some(key) = map.next_key()? {
                     match key {
                         Field::Path => {
@@ -141,6 +471,66 @@ impl<'de> Deserialize<'de> for TextureImage {
                             }
                             height_ratio = Some(map.next_value()?);
                         }
+                        Field::EmissionScale => {
+                            if emission_scale.is_some() {
+                                return Err(de::Error::duplicate_field("emission_scale"));
+                            }
+                            emission_scale = Some(map.next_value()?);
+                        }
+                        Field::Absorption => {
+                            if absorption.is_some() {
+                                return Err(de::Error::duplicate_field("absorption"));
+                            }
+                            absorption = Some(map.next_value()?);
+                        }
+                        Field::MeanFreePath => {
+                            if mean_free_path.is_some() {
+                                return Err(de::Error::duplicate_field("mean_free_path"));
+                            }
+                            mean_free_path = Some(map.next_value()?);
+                        }
+                        Field::Dispersion => {
+                            if dispersion.is_some() {
+                                return Err(de::Error::duplicate_field("dispersion"));
+                            }
+                            dispersion = Some(map.next_value()?);
+                        }
+                        Field::Mix => {
+                            if mix.is_some() {
+                                return Err(de::Error::duplicate_field("mix"));
+                            }
+                            mix = Some(map.next_value()?);
+                        }
+                        Field::Coat => {
+                            if coat.is_some() {
+                                return Err(de::Error::duplicate_field("coat"));
+                            }
+                            coat = Some(map.next_value()?);
+                        }
+                        Field::Filter => {
+                            if filter.is_some() {
+                                return Err(de::Error::duplicate_field("filter"));
+                            }
+                            filter = Some(map.next_value()?);
+                        }
+                        Field::Wrap => {
+                            if wrap.is_some() {
+                                return Err(de::Error::duplicate_field("wrap"));
+                            }
+                            wrap = Some(map.next_value()?);
+                        }
+                        Field::Alpha => {
+                            if alpha.is_some() {
+                                return Err(de::Error::duplicate_field("alpha"));
+                            }
+                            alpha = Some(map.next_value()?);
+                        }
+                        Field::AlphaThreshold => {
+                            if alpha_threshold.is_some() {
+                                return Err(de::Error::duplicate_field("alpha_threshold"));
+                            }
+                            alpha_threshold = Some(map.next_value()?);
+                        }
                     }
                 }
                 let path = path.ok_or_else(|| de::Error::missing_field("path"))?;
@@ -149,10 +539,113 @@ impl<'de> Deserialize<'de> for TextureImage {
                     width_ratio.ok_or_else(|| de::Error::missing_field("width_ratio"))?;
                 let height_ratio =
                     height_ratio.ok_or_else(|| de::Error::missing_field("height_ratio"))?;
-                Ok(TextureImage::new(path, material, width_ratio, height_ratio))
+                let mut img = TextureImage::new(path, material, width_ratio, height_ratio);
+                img.emission_scale = emission_scale.unwrap_or_default();
+                img.absorption = absorption.unwrap_or_default();
+                img.mean_free_path = mean_free_path.unwrap_or_default();
+                img.dispersion = dispersion;
+                img.mix = mix;
+                img.coat = coat;
+                img.filter = filter.unwrap_or_default();
+                img.wrap = wrap.unwrap_or_default();
+                img.alpha = alpha.unwrap_or_default();
+                img.alpha_threshold = alpha_threshold.unwrap_or_else(default_alpha_threshold);
+                if img.filter == TextureFilter::Trilinear {
+                    img.build_mipmap();
+                }
+                Ok(img)
             }
         }
 
         deserializer.deserialize_map(TextureImageVisitor {})
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a diffuse texture of the image, the tests change the fields they check
+    fn texture(image: Image) -> TextureImage {
+        TextureImage {
+            path: String::new(),
+            material: Material::Diffuse,
+            width_ratio: 1.0,
+            height_ratio: 1.0,
+            emission_scale: 0.0,
+            absorption: Vct::zero(),
+            mean_free_path: Vct::zero(),
+            dispersion: None,
+            mix: None,
+            coat: None,
+            filter: TextureFilter::Nearest,
+            wrap: Wrap::Repeat,
+            alpha: Alpha::Cutout,
+            alpha_threshold: 0.5,
+            image,
+            mipmap: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let mut image = Image::new(2, 1);
+        image.set(1, 0, Vct::one());
+        let mut img = texture(image);
+        assert_eq!(img.lookup(-0.5, 0.5, 0.0).0, 1.0);
+        img.wrap = Wrap::Clamp;
+        assert_eq!(img.lookup(-0.5, 0.5, 0.0).0, 0.0);
+        img.wrap = Wrap::Mirror;
+        assert_eq!(img.lookup(2.5, 0.5, 0.0).0, 1.0);
+        img.filter = TextureFilter::Bilinear;
+        assert_eq!(img.lookup(1.0, 0.5, 0.0).0, 0.5);
+        img.filter = TextureFilter::Trilinear;
+        img.build_mipmap();
+        assert_eq!(img.mipmap.len(), 1);
+        assert_eq!(img.lookup(0.5, 0.5, 2.0).0, 0.5);
+        assert_eq!(img.lookup(0.5, 0.5, 1.0).0, 0.0);
+        assert_eq!(img.texel((1.0, 0.5, 0.0, 1.0)).emission, Vct::zero());
+        img.emission_scale = 2.0;
+        let t = img.texel((1.0, 0.5, 0.0, 1.0));
+        assert_eq!((t.emission, t.color), (Vct::new(2.0, 1.0, 0.0), Vct::new(1.0, 0.5, 0.0)));
+    }
+
+    #[test]
+    fn alpha() {
+        let mut img = texture(Image::new(1, 1));
+        img.material = Material::Specular;
+        let (hole, solid) = ((1.0, 1.0, 1.0, 0.2), (1.0, 1.0, 1.0, 0.8));
+        assert!(!img.opaque(|| hole) && img.opaque(|| solid));
+        assert_eq!(img.texel(solid).material, Material::Specular);
+        img.alpha = Alpha::Diffuse;
+        assert!(img.opaque(|| hole));
+        assert_eq!(img.texel(hole).material, Material::Diffuse);
+        assert_eq!(img.texel((1.0, 1.0, 1.0, 0.0)).material, Material::Specular);
+    }
+
+    #[test]
+    fn dispersion() {
+        // N-BK7 glass
+        let cauchy = Dispersion::Cauchy { a: 1.5046, b: 0.0042 };
+        let sellmeier = Dispersion::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        };
+        for d in [cauchy, sellmeier].iter() {
+            assert!((d.ior(Dispersion::D_LINE) - 1.5168).abs() < 1e-3);
+            assert!(d.ior(450.0) > d.ior(650.0));
+        }
+    }
+
+    #[test]
+    fn layers() {
+        let layer = |m: &str| format!(r#"{{"material": "{}"}}"#, m);
+        let mix = |m: &str| format!(r#"{{"first": {}, "second": {}}}"#, layer("diffuse"), layer(m));
+        assert!(serde_json::from_str::<Mix>(&mix("specular")).is_ok());
+        assert!(serde_json::from_str::<Mix>(&mix("coated")).is_err());
+        assert!(serde_json::from_str::<Mix>(&mix("mix")).is_err());
+        let coat = |m: &str| format!(r#"{{"base": {}}}"#, layer(m));
+        assert!(serde_json::from_str::<Coat>(&coat("mix")).is_ok());
+        assert!(serde_json::from_str::<Coat>(&coat("coated")).is_err());
+    }
+}
@@ -1,13 +1,28 @@
-use crate::{linalg::Vct, Deserialize, Serialize};
+use crate::{linalg::Vct, Deserialize, Flt, Serialize};
 
+// Width and spread describe a cone around the ray for filtering textures, camera rays have
+// the one of their pixel and the others are treated as thin.
 #[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Ray {
     pub origin: Vct,
     pub direct: Vct,
+    #[serde(default)]
+    pub width: Flt,
+    #[serde(default)]
+    pub spread: Flt, // growth of the width per unit length
 }
 
 impl Ray {
     pub fn new(origin: Vct, direct: Vct) -> Self {
-        Self { origin, direct }
+        Self { origin, direct, width: 0.0, spread: 0.0 }
+    }
+
+    pub fn with_cone(self, width: Flt, spread: Flt) -> Self {
+        Self { width, spread, ..self }
+    }
+
+    // the width of the cone at distance t
+    pub fn footprint(&self, t: Flt) -> Flt {
+        self.width + self.spread * t
     }
 }
@@ -0,0 +1,246 @@
+use crate::{
+    linalg::Vct,
+    utils::{sampler::hash, Image},
+    Deserialize, Flt, Serialize,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AovPass {
+    Depth,
+    Position,
+    Normal,
+    Albedo,
+    Object,
+    Material,
+    Direct,
+    Indirect,
+    Emission,
+}
+
+impl AovPass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AovPass::Depth => "depth",
+            AovPass::Position => "position",
+            AovPass::Normal => "normal",
+            AovPass::Albedo => "albedo",
+            AovPass::Object => "object",
+            AovPass::Material => "material",
+            AovPass::Direct => "direct",
+            AovPass::Indirect => "indirect",
+            AovPass::Emission => "emission",
+        }
+    }
+
+    // IDs are not averaged, a pixel keeps the one of its first sample
+    fn is_id(&self) -> bool {
+        *self == AovPass::Object || *self == AovPass::Material
+    }
+}
+
+// Extra passes of PT, "{}" in path is replaced by the name of the pass. Files ending with
+// .pfm keep the raw values, the others are scaled to be viewable.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Aovs {
+    pub path: String,
+    pub passes: Vec<AovPass>,
+}
+
+// What the camera ray sees at its first hit. Misses are all zero, object and material IDs
+// start from 1 (0 is the background), material IDs follow the order of Material.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AovSample {
+    pub depth: Flt, // along the view direction
+    pub position: Vct,
+    pub normal: Vct, // facing the camera
+    pub albedo: Vct,
+    pub object: usize,
+    pub material: usize,
+    pub direct: Vct, // light arriving after one bounce
+    pub indirect: Vct,
+    pub emission: Vct,
+}
+
+impl AovSample {
+    fn get(&self, pass: AovPass) -> Vct {
+        let s = |x: Flt| Vct::new(x, x, x);
+        match pass {
+            AovPass::Depth => s(self.depth),
+            AovPass::Position => self.position,
+            AovPass::Normal => self.normal,
+            AovPass::Albedo => self.albedo,
+            AovPass::Object => s(self.object as Flt),
+            AovPass::Material => s(self.material as Flt),
+            AovPass::Direct => self.direct,
+            AovPass::Indirect => self.indirect,
+            AovPass::Emission => self.emission,
+        }
+    }
+}
+
+// the samples of one pixel
+#[derive(Clone, Debug, Default)]
+pub struct AovPixel {
+    pub sum: Vec<Vct>,
+    pub n: usize,
+}
+
+impl AovPixel {
+    pub fn add(&mut self, passes: &[AovPass], s: &AovSample) {
+        if self.n == 0 {
+            self.sum = passes.iter().map(|&p| s.get(p)).collect();
+        } else {
+            passes.iter().zip(self.sum.iter_mut()).filter(|(p, _)| !p.is_id()).for_each(
+                |(&p, v)| {
+                    *v += s.get(p);
+                },
+            );
+        }
+        self.n += 1;
+    }
+}
+
+// per pixel averages of the passes, y goes up like in Film
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AovFilm {
+    pub w: usize,
+    pub h: usize,
+    pub passes: Vec<AovPass>,
+    pub sum: Vec<Vct>, // passes.len() values per pixel
+    pub n: Vec<usize>,
+}
+
+impl AovFilm {
+    pub fn new(passes: &[AovPass], w: usize, h: usize) -> Self {
+        let len = passes.len();
+        Self {
+            w,
+            h,
+            passes: passes.to_vec(),
+            sum: vec![Vct::zero(); w * h * len],
+            n: vec![0; w * h],
+        }
+    }
+
+    // forget the samples of a pixel
+    pub fn clear(&mut self, index: usize) {
+        let len = self.passes.len();
+        self.sum[index * len..(index + 1) * len].iter_mut().for_each(|v| *v = Vct::zero());
+        self.n[index] = 0;
+    }
+
+    pub fn merge(&mut self, index: usize, px: &AovPixel) {
+        if px.n == 0 {
+            return;
+        }
+        let len = self.passes.len();
+        let first = self.n[index] == 0;
+        for (i, p) in self.passes.iter().enumerate() {
+            if first || !p.is_id() {
+                self.sum[index * len + i] += px.sum[i];
+            }
+        }
+        self.n[index] += px.n;
+    }
+
+    fn get(&self, index: usize, i: usize) -> Vct {
+        let v = self.sum[index * self.passes.len() + i];
+        if self.n[index] == 0 || self.passes[i].is_id() {
+            return v;
+        }
+        v / self.n[index] as Flt
+    }
+
+    // the averages of a pass, ordered like an Image
+    pub fn values(&self, pass: AovPass) -> Option<Vec<Vct>> {
+        let i = self.passes.iter().position(|&p| p == pass)?;
+        let (w, h) = (self.w, self.h);
+        Some((0..w * h).map(|index| self.get((h - index / w - 1) * w + index % w, i)).collect())
+    }
+
+    pub fn save(&self, cfg: &Aovs) {
+        for &pass in cfg.passes.iter() {
+            let path = cfg.path.replace("{}", pass.name());
+            let raw = path.ends_with(".pfm");
+            let values = match self.values(pass) {
+                Some(values) => values,
+                None => continue,
+            };
+            // 1% and 99% percentiles of the hits, used to scale depth and position,
+            // misses have a depth of 0
+            let hit = |v: &Vct| pass != AovPass::Depth || v.x > 0.0;
+            let hits: Vec<&Vct> = values.iter().filter(|v| hit(v)).collect();
+            let percentile = |f: &dyn Fn(&Vct) -> Flt| {
+                let mut a: Vec<Flt> = hits.iter().map(|v| f(v)).collect();
+                a.sort_by(|x, y| x.partial_cmp(y).unwrap());
+                let at =
+                    |q: Flt| a.get(((a.len() as Flt - 1.0) * q) as usize).cloned().unwrap_or(0.0);
+                (at(0.01), at(0.99))
+            };
+            let (x, y, z) = (percentile(&|v| v.x), percentile(&|v| v.y), percentile(&|v| v.z));
+            let scale = |v: Vct| {
+                let f = |x: Flt, (l, h): (Flt, Flt)| {
+                    if h > l {
+                        ((x - l) / (h - l)).clamp(0.0, 1.0)
+                    } else {
+                        0.0
+                    }
+                };
+                Vct::new(f(v.x, x), f(v.y, y), f(v.z, z))
+            };
+            let mut p = Image::new(self.w, self.h);
+            for y in 0..self.h {
+                for x in 0..self.w {
+                    let v = values[y * self.w + x];
+                    let c = match pass {
+                        _ if raw => v,
+                        AovPass::Depth if hit(&v) => Vct::one() - scale(v),
+                        AovPass::Position => scale(v),
+                        AovPass::Normal => (v + Vct::one()) * 0.5,
+                        AovPass::Object | AovPass::Material => id_color(v.x as usize),
+                        _ => v,
+                    };
+                    p.set(x, y, c);
+                }
+            }
+            if raw {
+                p.save_pfm(&path);
+            } else {
+                p.save_png(&path);
+            }
+        }
+    }
+}
+
+// a distinct color for every id, black for the background
+fn id_color(id: usize) -> Vct {
+    if id == 0 {
+        return Vct::zero();
+    }
+    let h = hash(id as u64);
+    let c = |s: u64| 0.2 + 0.8 * ((h >> s) & 0xff) as Flt / 255.0;
+    Vct::new(c(0), c(8), c(16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge() {
+        let passes = [AovPass::Depth, AovPass::Object];
+        let sample = |depth, object| AovSample { depth, object, ..Default::default() };
+        let mut film = AovFilm::new(&passes, 2, 1);
+        let mut px = AovPixel::default();
+        px.add(&passes, &sample(1.0, 3));
+        px.add(&passes, &sample(2.0, 5));
+        film.merge(1, &px);
+        let mut px = AovPixel::default();
+        px.add(&passes, &sample(6.0, 7));
+        film.merge(1, &px);
+        assert_eq!(film.get(1, 0), Vct::new(3.0, 3.0, 3.0));
+        assert_eq!(film.get(1, 1), Vct::new(3.0, 3.0, 3.0));
+        assert_eq!(film.get(0, 0), Vct::zero());
+    }
+}
@@ -1,6 +1,14 @@
-use crate::{linalg::Vct, Deserialize, Flt, Serialize};
+use crate::{
+    geo::TextureImage,
+    linalg::{Ray, Vct},
+    utils::Image,
+    Deserialize, Flt, Serialize, PI,
+};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeStruct, Serializer};
+use std::fmt;
 
-#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Camera {
     pub origin: Vct,
     pub direct: Vct,
@@ -8,6 +16,105 @@ pub struct Camera {
     pub plane_distance: Flt,
     pub focal_distance: Flt,
     pub aperture: Flt,
+    #[serde(default)]
+    pub aperture_shape: Aperture,
+    #[serde(default)]
+    pub cat_eye: Flt, // strength of the barrel occlusion, 0 means no cat's eye
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Aperture {
+    #[default]
+    Circle,
+    Polygon {
+        blades: usize,
+        rotation: Flt,
+    },
+    Image(ApertureImage),
+}
+
+#[derive(Clone, Debug)]
+pub struct ApertureImage {
+    pub path: String,
+    pub image: Image,
+    row: Vec<Flt>,
+    col: Vec<Flt>,
+}
+
+impl ApertureImage {
+    pub fn new(path: String) -> Self {
+        let image = TextureImage::load(&path);
+        let (w, h) = (image.w, image.h);
+        let (mut row, mut col) = (vec![0.0; h + 1], vec![0.0; h * (w + 1)]);
+        for y in 0..h {
+            for x in 0..w {
+                let c = image.get(x, y);
+                col[y * (w + 1) + x + 1] = col[y * (w + 1) + x] + (c.0 + c.1 + c.2) / 3.0;
+            }
+            row[y + 1] = row[y] + col[y * (w + 1) + w];
+        }
+        if row[h] <= 0.0 {
+            panic!("Aperture image {} is completely black", path);
+        }
+        Self { path, image, row, col }
+    }
+
+    fn find(cdf: &[Flt], v: Flt) -> (usize, Flt) {
+        let v = v * cdf[cdf.len() - 1];
+        let (mut l, mut r) = (0, cdf.len() - 2);
+        while l < r {
+            let mid = (l + r + 1) >> 1;
+            if cdf[mid] <= v {
+                l = mid;
+            } else {
+                r = mid - 1;
+            }
+        }
+        let d = cdf[l + 1] - cdf[l];
+        (l, if d > 0.0 { (v - cdf[l]) / d } else { 0.5 })
+    }
+
+    // sample by brightness, returns a point in [-1, 1] x [-1, 1]
+    pub fn sample(&self, u: Flt, v: Flt) -> (Flt, Flt) {
+        let (w, h) = (self.image.w, self.image.h);
+        let (y, fy) = Self::find(&self.row, v);
+        let (x, fx) = Self::find(&self.col[y * (w + 1)..(y + 1) * (w + 1)], u);
+        ((x as Flt + fx) / w as Flt * 2.0 - 1.0, (y as Flt + fy) / h as Flt * 2.0 - 1.0)
+    }
+}
+
+impl Aperture {
+    // map a uniform sample in [0, 1)^2 onto the unit aperture
+    pub fn sample(&self, u: Flt, v: Flt) -> (Flt, Flt) {
+        match self {
+            Aperture::Circle => {
+                let (a, b) = (u * 2.0 - 1.0, v * 2.0 - 1.0);
+                if a == 0.0 && b == 0.0 {
+                    return (0.0, 0.0);
+                }
+                let (r, theta) = if a.abs() > b.abs() {
+                    (a, PI / 4.0 * (b / a))
+                } else {
+                    (b, PI / 2.0 - PI / 4.0 * (a / b))
+                };
+                (r * theta.cos(), r * theta.sin())
+            },
+            Aperture::Polygon { blades, rotation } => {
+                let n = (*blades).max(3);
+                let k = u * n as Flt;
+                let i = (k as usize).min(n - 1);
+                let (s, t) = ((k - i as Flt).sqrt(), v);
+                let step = PI * 2.0 / n as Flt;
+                let a1 = rotation.to_radians() + step * i as Flt;
+                let a2 = a1 + step;
+                let x = s * ((1.0 - t) * a1.cos() + t * a2.cos());
+                let y = s * ((1.0 - t) * a1.sin() + t * a2.sin());
+                (x, y)
+            },
+            Aperture::Image(img) => img.sample(u, v),
+        }
+    }
 }
 
 impl Camera {
@@ -19,6 +126,119 @@ impl Camera {
         focal_distance: Flt,
         aperture: Flt,
     ) -> Self {
-        Self { origin, direct, view_angle_scale, plane_distance, focal_distance, aperture }
+        Self {
+            origin,
+            direct,
+            view_angle_scale,
+            plane_distance,
+            focal_distance,
+            aperture,
+            aperture_shape: Aperture::Circle,
+            cat_eye: 0.0,
+        }
+    }
+
+    // (fx, fy): position on the film in pixels, (u, v): aperture sample
+    // returns None if the ray is blocked by the lens barrel
+    pub fn ray(&self, w: usize, h: usize, fx: Flt, fy: Flt, u: Flt, v: Flt) -> Option<Ray> {
+        let (fw, fh) = (w as Flt, h as Flt);
+        let cx = Vct::new(fw * self.view_angle_scale / fh, 0.0, 0.0);
+        let cy = (cx % self.direct).norm() * self.view_angle_scale;
+        let direct = self.direct.norm();
+        let (sx, sy) = (fx / fw - 0.5, fy / fh - 0.5);
+        let d = cx * sx + cy * sy + direct;
+        let mut r = Vct::zero();
+        if self.aperture > 0.0 {
+            let (a, b) = self.aperture_shape.sample(u, v);
+            if self.cat_eye > 0.0 {
+                let (ox, oy) = (sx * 2.0 * self.cat_eye, sy * 2.0 * self.cat_eye);
+                if (a - ox) * (a - ox) + (b - oy) * (b - oy) > 1.0 {
+                    return None;
+                }
+            }
+            let lv = cy.norm();
+            let lu = (direct % lv).norm();
+            r = (lu * a + lv * b) * self.aperture;
+        }
+        let o = self.origin + r + d * self.plane_distance;
+        // a pixel spans this angle
+        let spread = self.view_angle_scale / fh / d.len2();
+        let ray = Ray::new(o, (d.norm() * self.focal_distance - r).norm());
+        Some(ray.with_cone(spread * self.plane_distance * d.len(), spread))
+    }
+}
+
+impl Serialize for ApertureImage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("aperture_image", 1)?;
+        s.serialize_field("path", &self.path)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ApertureImage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ApertureImageVisitor;
+
+        #[derive(Deserialize)]
+        #[serde(field_identifier, rename_all = "snake_case")]
+        enum Field {
+            Path,
+        }
+
+        impl<'de> Visitor<'de> for ApertureImageVisitor {
+            type Value = ApertureImage;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("ApertureImage")
+            }
+
+            fn visit_map<V>(self, mut map: V) -> Result<ApertureImage, V::Error>
+            where
+                V: MapAccess<'de>,
+            {
+                let mut path = None;
+                while let Some(key) = map.next_key()? {
+                    match key {
+                        Field::Path => {
+                            if path.is_some() {
+                                return Err(de::Error::duplicate_field("path"));
+                            }
+                            path = Some(map.next_value()?);
+                        },
+                    }
+                }
+                let path = path.ok_or_else(|| de::Error::missing_field("path"))?;
+                Ok(ApertureImage::new(path))
+            }
+        }
+
+        deserializer.deserialize_map(ApertureImageVisitor {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aperture() {
+        let shapes = [Aperture::Circle, Aperture::Polygon { blades: 6, rotation: 15.0 }];
+        for shape in shapes.iter() {
+            for i in 0..=20 {
+                for j in 0..=20 {
+                    let (x, y) = shape.sample(i as Flt / 20.0, j as Flt / 20.0);
+                    assert!(x * x + y * y <= 1.0 + 1e-9);
+                }
+            }
+        }
+        let (x, y) = Aperture::Circle.sample(1.0, 0.5);
+        assert!((x - 1.0).abs() < 1e-9 && y.abs() < 1e-9);
     }
 }
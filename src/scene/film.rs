@@ -0,0 +1,162 @@
+use super::Filter;
+use crate::{linalg::Vct, utils::Image, Deserialize, Flt, Serialize, EPS};
+
+// Accumulates filtered samples for the pixels [x, x + w) x [y, y + h) of an image.
+// Coordinates are the ones of the camera, i.e. y goes up.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Film {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+    pub sum: Vec<Vct>,
+    pub weight: Vec<Flt>,
+}
+
+impl Film {
+    pub fn new(x: usize, y: usize, w: usize, h: usize) -> Self {
+        Self { x, y, w, h, sum: vec![Vct::zero(); w * h], weight: vec![0.0; w * h] }
+    }
+
+    // a film able to receive every sample taken inside [x0, x1) x [y0, y1), clipped to the
+    // window of the render so that the pixels outside of it are left alone
+    pub fn around(
+        x0: usize,
+        y0: usize,
+        x1: usize,
+        y1: usize,
+        filter: &Filter,
+        window: (usize, usize, usize, usize),
+    ) -> Self {
+        let r = (filter.radius() - 0.5).max(0.0).ceil() as usize;
+        let (x0, y0) = (x0.saturating_sub(r).max(window.0), y0.saturating_sub(r).max(window.1));
+        let (x1, y1) = ((x1 + r).min(window.2), (y1 + r).min(window.3));
+        Self::new(x0, y0, x1 - x0, y1 - y0)
+    }
+
+    // splat a sample at (fx, fy) (in pixels of the whole image) to all pixels covered by the filter
+    pub fn add(&mut self, fx: Flt, fy: Flt, c: Vct, filter: &Filter) {
+        let r = filter.radius();
+        let (lx, ly) = ((fx - 0.5 - r).ceil(), (fy - 0.5 - r).ceil());
+        let (rx, ry) = ((fx - 0.5 + r).floor(), (fy - 0.5 + r).floor());
+        let x0 = lx.max(self.x as Flt) as usize;
+        let y0 = ly.max(self.y as Flt) as usize;
+        let x1 = (rx + 1.0).min((self.x + self.w) as Flt).max(0.0) as usize;
+        let y1 = (ry + 1.0).min((self.y + self.h) as Flt).max(0.0) as usize;
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let wt = filter.eval(x as Flt + 0.5 - fx, y as Flt + 0.5 - fy);
+                if wt != 0.0 {
+                    let i = (y - self.y) * self.w + x - self.x;
+                    self.sum[i] += c * wt;
+                    self.weight[i] += wt;
+                }
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Film) {
+        for y in other.y.max(self.y)..(other.y + other.h).min(self.y + self.h) {
+            for x in other.x.max(self.x)..(other.x + other.w).min(self.x + self.w) {
+                let i = (y - self.y) * self.w + x - self.x;
+                let j = (y - other.y) * other.w + x - other.x;
+                self.sum[i] += other.sum[j];
+                self.weight[i] += other.weight[j];
+            }
+        }
+    }
+
+    // forget the samples of the pixels [x0, x1) x [y0, y1)
+    pub fn clear(&mut self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        for y in y0.max(self.y)..y1.min(self.y + self.h) {
+            for x in x0.max(self.x)..x1.min(self.x + self.w) {
+                let i = (y - self.y) * self.w + x - self.x;
+                self.sum[i] = Vct::zero();
+                self.weight[i] = 0.0;
+            }
+        }
+    }
+
+    // Zero where the weights nearly cancel out, which the negative lobes of Mitchell and Lanczos
+    // allow with few samples.
+    pub fn get(&self, x: usize, y: usize) -> Vct {
+        let i = (y - self.y) * self.w + x - self.x;
+        if self.weight[i].abs() < EPS {
+            return Vct::zero();
+        }
+        self.sum[i] / self.weight[i]
+    }
+
+    // write to an image whose y goes down
+    pub fn write(&self, p: &mut Image) {
+        for y in self.y..self.y + self.h {
+            for x in self.x..self.x + self.w {
+                p.set(x, p.h - y - 1, self.get(x, y));
+            }
+        }
+    }
+}
+
+// running mean and variance of the luminance of the samples taken in a pixel
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Stat {
+    pub n: usize,
+    pub mean: Flt,
+    pub m2: Flt,
+}
+
+impl Stat {
+    pub fn add(&mut self, x: Flt) {
+        self.n += 1;
+        let d = x - self.mean;
+        self.mean += d / self.n as Flt;
+        self.m2 += d * (x - self.mean);
+    }
+
+    // variance of the mean
+    pub fn variance(&self) -> Flt {
+        if self.n < 2 {
+            return 0.0;
+        }
+        (self.m2 / (self.n - 1) as Flt / self.n as Flt).max(0.0)
+    }
+
+    // relative standard error of the mean
+    pub fn error(&self) -> Flt {
+        if self.n < 2 {
+            return Flt::INFINITY;
+        }
+        self.variance().sqrt() / self.mean.abs().max(EPS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn around() {
+        let tent = Filter::Tent { radius: 1.0 };
+        let film = Film::around(4, 4, 8, 8, &tent, (0, 0, 16, 16));
+        assert_eq!((film.x, film.y, film.w, film.h), (3, 3, 6, 6));
+        let film = Film::around(4, 4, 8, 8, &tent, (4, 4, 8, 16));
+        assert_eq!((film.x, film.y, film.w, film.h), (4, 4, 4, 5));
+    }
+
+    #[test]
+    fn negative_lobes() {
+        let filter = Filter::Lanczos { radius: 3.0 };
+        let mut film = Film::new(0, 0, 12, 12);
+        film.add(5.3, 5.7, Vct::one(), &filter);
+        for y in 0..12 {
+            for x in 0..12 {
+                let c = film.get(x, y);
+                assert!(c.x.is_finite() && c.x >= 0.0 && c.x <= 1.0 + 1e-9, "{} {} {:?}", x, y, c);
+            }
+        }
+        // a pixel whose weights cancel out
+        film.sum[0] = Vct::one();
+        film.weight[0] = 1e-9;
+        assert_eq!(film.get(0, 0), Vct::zero());
+    }
+}
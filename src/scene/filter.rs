@@ -0,0 +1,123 @@
+use crate::{Deserialize, Flt, Serialize, PI};
+
+fn default_alpha() -> Flt {
+    2.0
+}
+
+fn default_b() -> Flt {
+    1.0 / 3.0
+}
+
+fn default_c() -> Flt {
+    1.0 / 3.0
+}
+
+// separable pixel reconstruction filters, radius is in pixels
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Filter {
+    Box {
+        radius: Flt,
+    },
+    Tent {
+        radius: Flt,
+    },
+    Gaussian {
+        radius: Flt,
+        #[serde(default = "default_alpha")]
+        alpha: Flt,
+    },
+    Mitchell {
+        radius: Flt,
+        #[serde(default = "default_b")]
+        b: Flt,
+        #[serde(default = "default_c")]
+        c: Flt,
+    },
+    Lanczos {
+        radius: Flt,
+    },
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Tent { radius: 1.0 }
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> Flt {
+        match *self {
+            Filter::Box { radius } => radius,
+            Filter::Tent { radius } => radius,
+            Filter::Gaussian { radius, .. } => radius,
+            Filter::Mitchell { radius, .. } => radius,
+            Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    fn eval1(&self, x: Flt) -> Flt {
+        let x = x.abs();
+        if x > self.radius() {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { radius } => radius - x,
+            Filter::Gaussian { radius, alpha } => {
+                ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.0)
+            },
+            Filter::Mitchell { radius, b, c } => {
+                let x = 2.0 * x / radius;
+                let x2 = x * x;
+                let x3 = x2 * x;
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x3
+                        + (6.0 * b + 30.0 * c) * x2
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x3
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x2
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            },
+            Filter::Lanczos { radius } => {
+                let sinc = |x: Flt| if x < 1e-5 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                sinc(x) * sinc(x / radius)
+            },
+        }
+    }
+
+    // (x, y) is the offset from the pixel center
+    pub fn eval(&self, x: Flt, y: Flt) -> Flt {
+        self.eval1(x) * self.eval1(y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval() {
+        let filters = [
+            Filter::Box { radius: 0.5 },
+            Filter::Tent { radius: 1.0 },
+            Filter::Gaussian { radius: 1.5, alpha: 2.0 },
+            Filter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 },
+            Filter::Lanczos { radius: 3.0 },
+        ];
+        for f in filters.iter() {
+            let r = f.radius();
+            assert!(f.eval(0.0, 0.0) > 0.0);
+            assert_eq!(f.eval(r + 0.01, 0.0), 0.0);
+            assert!((f.eval(0.3, -0.2) - f.eval(-0.3, 0.2)).abs() < 1e-12);
+        }
+        let m = Filter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 };
+        assert!(m.eval(2.0, 0.0).abs() < 1e-12);
+        assert!((m.eval(0.0, 0.0) - (8.0 / 9.0) * (8.0 / 9.0)).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{
+    linalg::{Transform, Vct},
+    Deserialize, Flt, Serialize, PI,
+};
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use std::fs;
+
+// An angular intensity profile from an IES LM-63 file of type C photometry, scaled so that its
+// brightest direction is 1. Vertical angles start at z, the direction the light shines along,
+// and horizontal angles go from x towards y.
+#[derive(Clone, Debug)]
+pub struct Ies {
+    pub path: String,
+    vertical: Vec<Flt>,     // in degrees, increasing
+    horizontal: Vec<Flt>,   // in degrees, increasing
+    candela: Vec<Vec<Flt>>, // for every horizontal angle, along the vertical ones
+}
+
+// the two neighbours of x in a, and the weight of the second one
+fn bracket(a: &[Flt], x: Flt) -> (usize, usize, Flt) {
+    let i = a.partition_point(|&v| v <= x);
+    if i == 0 {
+        (0, 0, 0.0)
+    } else if i == a.len() {
+        (i - 1, i - 1, 0.0)
+    } else {
+        (i - 1, i, (x - a[i - 1]) / (a[i] - a[i - 1]))
+    }
+}
+
+impl Ies {
+    pub fn load(path: &str) -> Option<Self> {
+        Self::parse(path.to_owned(), &fs::read_to_string(path).ok()?)
+    }
+
+    pub fn parse(path: String, data: &str) -> Option<Self> {
+        // keywords come before the tilt line, numbers after it
+        let mut lines = data.lines();
+        let tilt = lines.find(|l| l.trim_start().starts_with("TILT="))?;
+        let mut num = lines
+            .flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<Flt>().ok());
+        let mut next = || num.next().flatten();
+        if tilt.trim() == "TILT=INCLUDE" {
+            // the lamp to luminaire geometry, then the tilt angles and their multipliers
+            next()?;
+            let n = next()? as usize;
+            (0..n * 2).try_for_each(|_| next().map(|_| ()))?;
+        }
+        let (_lamps, _lumens, multiplier) = (next()?, next()?, next()?);
+        let (nv, nh, kind) = (next()? as usize, next()? as usize, next()? as usize);
+        // units, the size of the luminaire, the ballast factors and the input watts
+        (0..7).try_for_each(|_| next().map(|_| ()))?;
+        if kind != 1 || nv == 0 || nh == 0 {
+            return None;
+        }
+        let vertical = (0..nv).map(|_| next()).collect::<Option<Vec<_>>>()?;
+        let horizontal = (0..nh).map(|_| next()).collect::<Option<Vec<_>>>()?;
+        let mut candela = (0..nh)
+            .map(|_| (0..nv).map(|_| next().map(|c| c * multiplier)).collect())
+            .collect::<Option<Vec<Vec<_>>>>()?;
+        let max = candela.iter().flatten().fold(0.0, |a: Flt, &b| a.max(b));
+        if max <= 0.0 {
+            return None;
+        }
+        candela.iter_mut().flatten().for_each(|c| *c /= max);
+        Some(Self { path, vertical, horizontal, candela })
+    }
+
+    // the profile along the unit direction d in the frame of the profile
+    pub fn at(&self, d: Vct) -> Flt {
+        let v = d.z.clamp(-1.0, 1.0).acos().to_degrees();
+        let (first, last) = (self.vertical[0], self.vertical[self.vertical.len() - 1]);
+        if v < first - 1e-6 || v > last + 1e-6 {
+            return 0.0;
+        }
+        let mut h = d.y.atan2(d.x).to_degrees();
+        if h < 0.0 {
+            h += 360.0;
+        }
+        // the horizontal angles cover a quarter or a half of the circle by symmetry
+        let end = self.horizontal[self.horizontal.len() - 1];
+        if (end - 90.0).abs() < 1e-6 {
+            h %= 180.0;
+            if h > 90.0 {
+                h = 180.0 - h;
+            }
+        } else if (end - 180.0).abs() < 1e-6 && h > 180.0 {
+            h = 360.0 - h;
+        }
+        let (h0, h1, wh) = bracket(&self.horizontal, h);
+        let (v0, v1, wv) = bracket(&self.vertical, v);
+        let at = |h: usize| self.candela[h][v0] * (1.0 - wv) + self.candela[h][v1] * wv;
+        at(h0) * (1.0 - wh) + at(h1) * wh
+    }
+
+    // the profile along the unit direction d of a light placed by transform
+    pub fn eval(&self, transform: &Transform, d: Vct) -> Flt {
+        let (x, y, z) = (transform.x().norm(), transform.y().norm(), transform.z().norm());
+        self.at(Vct::new(d.dot(x), d.dot(y), d.dot(z)))
+    }
+
+    // the average over the directions whose cosine with z is at least cos_max
+    pub fn mean(&self, cos_max: Flt) -> Flt {
+        let (n, m) = (64, 128);
+        let mut sum = 0.0;
+        for i in 0..n {
+            let cos = 1.0 - (i as Flt + 0.5) / n as Flt * (1.0 - cos_max);
+            let sin = (1.0 - cos * cos).max(0.0).sqrt();
+            for j in 0..m {
+                let phi = 2.0 * PI * (j as Flt + 0.5) / m as Flt;
+                sum += self.at(Vct::new(sin * phi.cos(), sin * phi.sin(), cos));
+            }
+        }
+        sum / (n * m) as Flt
+    }
+}
+
+impl Serialize for Ies {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.path)
+    }
+}
+
+// loaded from its path
+impl<'de> Deserialize<'de> for Ies {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let path = String::deserialize(deserializer)?;
+        Ies::load(&path).ok_or_else(|| de::Error::custom(format!("Invalid IES profile {}", path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOWN: &str = "IESNA:LM-63-2002\n[TEST] down\nTILT=NONE\n1 1000 2 3 2 1 2 0 0 0\n\
+                        1 1 100\n0 45 90\n0 90\n50 25 0\n50, 50, 0\n";
+
+    #[test]
+    fn ies() {
+        let ies = Ies::parse(String::new(), DOWN).unwrap();
+        let dir = |v: Flt, h: Flt| {
+            let (v, h) = (v.to_radians(), h.to_radians());
+            Vct::new(v.sin() * h.cos(), v.sin() * h.sin(), v.cos())
+        };
+        assert!((ies.at(dir(0.0, 0.0)) - 1.0).abs() < 1e-9);
+        assert!((ies.at(dir(45.0, 0.0)) - 0.5).abs() < 1e-9);
+        assert!((ies.at(dir(45.0, 90.0)) - 1.0).abs() < 1e-9);
+        assert!((ies.at(dir(22.5, 45.0)) - 0.875).abs() < 1e-9);
+        // symmetric in quadrants, and dark above the horizon
+        assert!((ies.at(dir(45.0, 270.0)) - 1.0).abs() < 1e-9);
+        assert!((ies.at(dir(45.0, 180.0)) - 0.5).abs() < 1e-9);
+        assert_eq!(ies.at(dir(120.0, 0.0)), 0.0);
+        assert!(ies.mean(-1.0) < 0.5 && ies.mean(-1.0) < ies.mean(0.5));
+        assert!(Ies::parse(String::new(), "TILT=NONE\n1 1000 1 3").is_none());
+    }
+}
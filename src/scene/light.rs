@@ -0,0 +1,187 @@
+use super::Ies;
+use crate::{
+    linalg::{Ray, Transform, Vct},
+    utils::Sampler,
+    Deserialize, Flt, Serialize, PI,
+};
+
+fn default_transform() -> Transform {
+    Transform::new(Vec::new())
+}
+
+// how far from the scene photons of directional lights start, in radii of the scene
+const FAR: Flt = 1e3;
+
+// Lights that rays can't hit, they are reached by shadow rays instead. They are placed by
+// their transform and shine along its z axis.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Light {
+    Point {
+        #[serde(default = "default_transform")]
+        transform: Transform,
+        intensity: Vct, // per steradian
+        #[serde(default)]
+        ies: Option<Ies>, // modulating the intensity, oriented by the transform
+    },
+    Spot {
+        #[serde(default = "default_transform")]
+        transform: Transform,
+        intensity: Vct,
+        angle: Flt, // between the axis and the edge of the cone, in degrees
+        #[serde(default)]
+        falloff: Flt, // the width of the soft edge inside the cone, in degrees
+        #[serde(default)]
+        ies: Option<Ies>,
+    },
+    Directional {
+        #[serde(default = "default_transform")]
+        transform: Transform,
+        irradiance: Vct, // on a surface facing the light
+        #[serde(default)]
+        diameter: Flt, // the angular size of the source in degrees, 0 for parallel light
+    },
+}
+
+// two directions across axis
+fn frame(axis: Vct) -> (Vct, Vct) {
+    let u = (if axis.x.abs() <= 0.1 { Vct::new(1.0, 0.0, 0.0) } else { Vct::new(0.0, 1.0, 0.0) }
+        % axis)
+        .norm();
+    (u, axis % u)
+}
+
+// a uniform direction in the cone around axis whose edge has cosine cos_max
+fn cone(axis: Vct, cos_max: Flt, rng: &mut dyn Sampler) -> Vct {
+    let cos = 1.0 - rng.gen() * (1.0 - cos_max);
+    let (sin, phi) = ((1.0 - cos * cos).max(0.0).sqrt(), 2.0 * PI * rng.gen());
+    let (u, v) = frame(axis);
+    ((u * phi.cos() + v * phi.sin()) * sin + axis * cos).norm()
+}
+
+// the part of the intensity going along d
+fn profile(ies: &Option<Ies>, transform: &Transform, d: Vct) -> Flt {
+    ies.as_ref().map_or(1.0, |ies| ies.eval(transform, d))
+}
+
+impl Light {
+    // the part of the intensity of a spot light going along d, smooth over the falloff
+    fn spot(axis: Vct, angle: Flt, falloff: Flt, d: Vct) -> Flt {
+        let cos = axis.dot(d);
+        let outer = angle.to_radians().cos();
+        let inner = (angle - falloff).max(0.0).to_radians().cos();
+        if cos >= inner {
+            1.0
+        } else if cos <= outer {
+            0.0
+        } else {
+            let t = (cos - outer) / (inner - outer);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+
+    // the direction from pos to the light, its distance and the irradiance on a surface facing it
+    pub fn sample(&self, pos: Vct, rng: &mut dyn Sampler) -> (Vct, Flt, Vct) {
+        match self {
+            Light::Point { transform, intensity, ies } => {
+                let d = transform.pos() - pos;
+                let l2 = d.len2();
+                let d = d / l2.sqrt();
+                (d, l2.sqrt(), *intensity * (profile(ies, transform, -d) / l2))
+            },
+            Light::Spot { transform, intensity, angle, falloff, ies } => {
+                let d = transform.pos() - pos;
+                let l2 = d.len2();
+                let d = d / l2.sqrt();
+                let k = Self::spot(transform.z().norm(), *angle, *falloff, -d)
+                    * profile(ies, transform, -d);
+                (d, l2.sqrt(), *intensity * (k / l2))
+            },
+            Light::Directional { transform, irradiance, diameter } => {
+                let cos_max = (diameter * 0.5).to_radians().cos();
+                (cone(-transform.z().norm(), cos_max, rng), Flt::INFINITY, *irradiance)
+            },
+        }
+    }
+
+    // the power of the light, directional ones over the sphere of the scene
+    pub fn power(&self, radius: Flt) -> Vct {
+        match self {
+            Light::Point { intensity, ies, .. } => {
+                *intensity * (4.0 * PI * ies.as_ref().map_or(1.0, |ies| ies.mean(-1.0)))
+            },
+            Light::Spot { intensity, angle, falloff, ies, .. } => {
+                // the soft edge averages to a half in cosines
+                let outer = angle.to_radians().cos();
+                let inner = (angle - falloff).max(0.0).to_radians().cos();
+                let k = ies.as_ref().map_or(1.0, |ies| ies.mean(outer));
+                *intensity * (2.0 * PI * (1.0 - (outer + inner) * 0.5) * k)
+            },
+            Light::Directional { irradiance, .. } => *irradiance * (PI * radius * radius),
+        }
+    }
+
+    // A photon leaving the light and its flux, which average to the power. Photons of
+    // directional lights come from far away and cover the sphere of the scene.
+    pub fn emit(&self, center: Vct, radius: Flt, rng: &mut dyn Sampler) -> (Ray, Vct) {
+        match self {
+            Light::Point { transform, intensity, ies } => {
+                let d = cone(Vct::new(0.0, 0.0, 1.0), -1.0, rng);
+                let k = 4.0 * PI * profile(ies, transform, d);
+                (Ray::new(transform.pos(), d), *intensity * k)
+            },
+            Light::Spot { transform, intensity, angle, falloff, ies } => {
+                let axis = transform.z().norm();
+                let cos_max = angle.to_radians().cos();
+                let d = cone(axis, cos_max, rng);
+                let k = Self::spot(axis, *angle, *falloff, d)
+                    * profile(ies, transform, d)
+                    * (2.0 * PI * (1.0 - cos_max));
+                (Ray::new(transform.pos(), d), *intensity * k)
+            },
+            Light::Directional { transform, irradiance, diameter } => {
+                // from a point of the disk across the axis
+                let axis = transform.z().norm();
+                let (a, b) = frame(axis);
+                let (r, phi) = (radius * rng.gen().sqrt(), 2.0 * PI * rng.gen());
+                let o = center + (a * phi.cos() + b * phi.sin()) * r - axis * (radius * FAR);
+                let d = cone(axis, (diameter * 0.5).to_radians().cos(), rng);
+                (Ray::new(o, d), *irradiance * (PI * radius * radius))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{linalg::TransformType, utils::Rng};
+
+    #[test]
+    fn lights() {
+        let up = Transform::new(vec![
+            TransformType::Rotate { axis: String::from("x"), degree: -90.0 },
+            TransformType::Shift { x: 0.0, y: 2.0, z: 0.0 },
+        ]);
+        let mut rng = Rng::new(3);
+        let point = Light::Point { transform: up.clone(), intensity: Vct::one(), ies: None };
+        let (d, dist, e) = point.sample(Vct::zero(), &mut rng);
+        assert!((d - Vct::new(0.0, 1.0, 0.0)).len() < 1e-9 && (dist - 2.0).abs() < 1e-9);
+        assert!((e.x - 0.25).abs() < 1e-9);
+        // a spot light pointing up doesn't light what is below it
+        let spot = Light::Spot {
+            transform: up,
+            intensity: Vct::one(),
+            angle: 30.0,
+            falloff: 10.0,
+            ies: None,
+        };
+        assert!(spot.sample(Vct::new(0.0, 4.0, 0.0), &mut rng).2.x > 0.99 / 4.0);
+        assert_eq!(spot.sample(Vct::zero(), &mut rng).2, Vct::zero());
+        // the photons of a spot light carry its power on average
+        let n = 100000;
+        let sum = (0..n).map(|_| spot.emit(Vct::zero(), 1.0, &mut rng).1.x).sum::<Flt>();
+        let power = spot.power(1.0).x;
+        assert!((sum / n as Flt - power).abs() < power * 0.02);
+    }
+}
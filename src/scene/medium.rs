@@ -0,0 +1,364 @@
+use crate::{
+    linalg::{Ray, Vct},
+    utils::{spectrum::spectral, Sampler},
+    Deserialize, Flt, Serialize, PI,
+};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeStruct, Serializer};
+use std::fmt;
+use std::fs;
+
+// Densities on a regular grid filling the box [min, max], interpolated trilinearly and
+// 0 outside. The file holds "nx ny nz" followed by the nx * ny * nz densities, x varying
+// the fastest.
+#[derive(Clone, Debug)]
+pub struct Grid {
+    pub path: String,
+    pub min: Vct,
+    pub max: Vct,
+    pub n: (usize, usize, usize),
+    pub data: Vec<Flt>,
+    pub max_density: Flt,
+}
+
+impl Grid {
+    pub fn new(path: String, min: Vct, max: Vct) -> Self {
+        let text =
+            fs::read_to_string(&path).unwrap_or_else(|e| panic!("Cannot open {}: {}", path, e));
+        let mut it = text.split_whitespace();
+        let mut next = || -> Flt {
+            let v = it.next().unwrap_or_else(|| panic!("{} is too short", path));
+            v.parse().unwrap_or_else(|_| panic!("Invalid number {} in {}", v, path))
+        };
+        let n = (next() as usize, next() as usize, next() as usize);
+        let data: Vec<Flt> = (0..n.0 * n.1 * n.2).map(|_| next()).collect();
+        let max_density = data.iter().cloned().fold(0.0, Flt::max);
+        Self { path, min, max, n, data, max_density }
+    }
+
+    pub fn density(&self, p: Vct) -> Flt {
+        let (nx, ny, nz) = self.n;
+        let q = (p - self.min) / (self.max - self.min);
+        let (gx, gy, gz) = (q.x * nx as Flt - 0.5, q.y * ny as Flt - 0.5, q.z * nz as Flt - 0.5);
+        let (x, y, z) = (gx.floor(), gy.floor(), gz.floor());
+        let (fx, fy, fz) = (gx - x, gy - y, gz - z);
+        let at = |dx: isize, dy: isize, dz: isize| {
+            let (x, y, z) = (x as isize + dx, y as isize + dy, z as isize + dz);
+            if x < 0 || y < 0 || z < 0 || x >= nx as isize || y >= ny as isize || z >= nz as isize {
+                return 0.0;
+            }
+            self.data[(z as usize * ny + y as usize) * nx + x as usize]
+        };
+        let lerp = |a: Flt, b: Flt, t: Flt| a + (b - a) * t;
+        let x00 = lerp(at(0, 0, 0), at(1, 0, 0), fx);
+        let x10 = lerp(at(0, 1, 0), at(1, 1, 0), fx);
+        let x01 = lerp(at(0, 0, 1), at(1, 0, 1), fx);
+        let x11 = lerp(at(0, 1, 1), at(1, 1, 1), fx);
+        lerp(lerp(x00, x10, fy), lerp(x01, x11, fy), fz)
+    }
+
+    // the part of [t0, t1] where r is inside the box
+    fn clip(&self, r: &Ray, mut t0: Flt, mut t1: Flt) -> Option<(Flt, Flt)> {
+        for d in 0..3 {
+            let inv = 1.0 / r.direct[d];
+            let (a, b) = ((self.min[d] - r.origin[d]) * inv, (self.max[d] - r.origin[d]) * inv);
+            t0 = t0.max(a.min(b));
+            t1 = t1.min(a.max(b));
+        }
+        if t0 < t1 {
+            Some((t0, t1))
+        } else {
+            None
+        }
+    }
+}
+
+// Scattering and absorption coefficients are per unit length, scaled by the grid density
+// when there is one. g is the asymmetry of the Henyey-Greenstein phase function.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Medium {
+    pub sigma_a: Vct,
+    pub sigma_s: Vct,
+    #[serde(default)]
+    pub g: Flt,
+    #[serde(default)]
+    pub grid: Option<Grid>,
+}
+
+pub enum Interaction {
+    Pass(Vct),         // reached t_max, with the weight of the path so far
+    Scatter(Ray, Vct), // continue with the ray sampled from the phase function
+    Absorb,
+}
+
+impl Medium {
+    // The medium of a subsurface material whose multiple scattering gives about the albedo,
+    // see Chiang et al., "Practical and Controllable Subsurface Scattering for Production
+    // Path Tracing".
+    pub fn subsurface(albedo: Vct, mean_free_path: Vct) -> Self {
+        let single = |a: Flt| {
+            let a = a.clamp(0.0, 0.999);
+            let b = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+            1.0 - b * b
+        };
+        let inv = |d: Flt| 1.0 / d.max(1e-4);
+        let d = mean_free_path;
+        let sigma_t = Vct::new(inv(d.x), inv(d.y), inv(d.z));
+        let alpha = Vct::new(single(albedo.x), single(albedo.y), single(albedo.z));
+        let sigma_s = sigma_t * alpha;
+        Self { sigma_a: sigma_t - sigma_s, sigma_s, g: 0.0, grid: None }
+    }
+
+    // Delta tracking with a majorant for all channels, the weights of the null and real
+    // collisions make up for channels whose coefficients are below it.
+    pub fn sample(
+        &self,
+        r: &Ray,
+        t_max: Flt,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> Interaction {
+        let (sigma_a, sigma_s) = (spectral(self.sigma_a, lambda), spectral(self.sigma_s, lambda));
+        let sigma_t = sigma_a + sigma_s;
+        let (max_density, (mut t, t_max)) = match &self.grid {
+            Some(grid) => match grid.clip(r, 0.0, t_max) {
+                Some(range) => (grid.max_density, range),
+                None => return Interaction::Pass(Vct::one()),
+            },
+            None => (1.0, (0.0, t_max)),
+        };
+        let majorant = sigma_t.x.max(sigma_t.y.max(sigma_t.z)) * max_density;
+        if majorant <= 0.0 {
+            return Interaction::Pass(Vct::one());
+        }
+        let avg = |v: Vct| (v.x + v.y + v.z) / 3.0;
+        let mut weight = Vct::one();
+        loop {
+            t -= (1.0 - rng.gen()).ln() / majorant;
+            if t >= t_max {
+                return Interaction::Pass(weight);
+            }
+            let pos = r.origin + r.direct * t;
+            let density = self.grid.as_ref().map_or(1.0, |g| g.density(pos));
+            let (sa, ss) = (sigma_a * density, sigma_s * density);
+            let sn = Vct::one() * majorant - sa - ss;
+            let (pa, ps) = (avg(sa) / majorant, avg(ss) / majorant);
+            let u = rng.gen();
+            if u < pa {
+                return Interaction::Absorb;
+            } else if u < pa + ps {
+                weight *= ss / (majorant * ps);
+                let d = self.phase(r.direct, rng.gen(), rng.gen());
+                return Interaction::Scatter(Ray::new(pos, d), weight);
+            }
+            weight *= sn / (majorant * (1.0 - pa - ps));
+        }
+    }
+
+    // Like sample, with the throughput of the path so far. The distance follows the
+    // coefficient of a channel picked by its throughput and is weighted by the average pdf
+    // of all channels, which keeps the weights bounded when the channels differ a lot like
+    // in subsurface scattering. Absorption is left to russian roulette.
+    pub fn sample_path(
+        &self,
+        r: &Ray,
+        t_max: Flt,
+        throughput: Vct,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> Interaction {
+        let sum = throughput.x + throughput.y + throughput.z;
+        if self.grid.is_some() || sum <= 0.0 {
+            return self.sample(r, t_max, lambda, rng);
+        }
+        let (sigma_a, sigma_s) = (spectral(self.sigma_a, lambda), spectral(self.sigma_s, lambda));
+        let sigma_t = sigma_a + sigma_s;
+        if sigma_t.x.max(sigma_t.y.max(sigma_t.z)) <= 0.0 {
+            return Interaction::Pass(Vct::one());
+        }
+        let q = throughput / sum;
+        let u = rng.gen();
+        let c = if u < q.x {
+            0
+        } else if u < q.x + q.y {
+            1
+        } else {
+            2
+        };
+        let t = if sigma_t[c] > 0.0 { -(1.0 - rng.gen()).ln() / sigma_t[c] } else { t_max };
+        let tr = |t: Flt| {
+            Vct::new((-sigma_t.x * t).exp(), (-sigma_t.y * t).exp(), (-sigma_t.z * t).exp())
+        };
+        if t >= t_max {
+            let tr = tr(t_max);
+            return Interaction::Pass(tr / q.dot(tr));
+        }
+        let tr = tr(t);
+        let mut weight = sigma_s * tr / q.dot(sigma_t * tr);
+        let max = |v: Vct| v.x.max(v.y.max(v.z));
+        let p = (max(throughput * weight) / max(throughput)).min(1.0);
+        if rng.gen() >= p {
+            return Interaction::Absorb;
+        }
+        weight /= p;
+        let d = self.phase(r.direct, rng.gen(), rng.gen());
+        Interaction::Scatter(Ray::new(r.origin + r.direct * t, d), weight)
+    }
+
+    // the density of the Henyey-Greenstein phase function for directions at cos to each other
+    pub fn phase_value(&self, cos: Flt) -> Flt {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos;
+        (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+    }
+
+    // sample the Henyey-Greenstein phase function around the direction d
+    pub fn phase(&self, d: Vct, u: Flt, v: Flt) -> Vct {
+        let g = self.g;
+        let cos = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let s = (1.0 - g * g) / (1.0 + g - 2.0 * g * u);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+        let a = (if d.x.abs() <= 0.1 { Vct::new(1.0, 0.0, 0.0) } else { Vct::new(0.0, 1.0, 0.0) }
+            % d)
+            .norm();
+        let b = d % a;
+        (a * (sin * phi.cos()) + b * (sin * phi.sin()) + d * cos).norm()
+    }
+}
+
+impl Serialize for Grid {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("grid", 3)?;
+        s.serialize_field("path", &self.path)?;
+        s.serialize_field("min", &self.min)?;
+        s.serialize_field("max", &self.max)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Grid {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct GridVisitor;
+
+        #[derive(Deserialize)]
+        #[serde(field_identifier, rename_all = "snake_case")]
+        enum Field {
+            Path,
+            Min,
+            Max,
+        }
+
+        impl<'de> Visitor<'de> for GridVisitor {
+            type Value = Grid;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("Grid")
+            }
+
+            fn visit_map<V>(self, mut map: V) -> Result<Grid, V::Error>
+            where
+                V: MapAccess<'de>,
+            {
+                let mut path = None;
+                let mut min = None;
+                let mut max = None;
+                while let Some(key) = map.next_key()? {
+                    match key {
+                        Field::Path => {
+                            if path.is_some() {
+                                return Err(de::Error::duplicate_field("path"));
+                            }
+                            path = Some(map.next_value()?);
+                        },
+                        Field::Min => {
+                            if min.is_some() {
+                                return Err(de::Error::duplicate_field("min"));
+                            }
+                            min = Some(map.next_value()?);
+                        },
+                        Field::Max => {
+                            if max.is_some() {
+                                return Err(de::Error::duplicate_field("max"));
+                            }
+                            max = Some(map.next_value()?);
+                        },
+                    }
+                }
+                let path = path.ok_or_else(|| de::Error::missing_field("path"))?;
+                let min = min.ok_or_else(|| de::Error::missing_field("min"))?;
+                let max = max.ok_or_else(|| de::Error::missing_field("max"))?;
+                Ok(Grid::new(path, min, max))
+            }
+        }
+
+        deserializer.deserialize_map(GridVisitor {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Rng;
+
+    #[test]
+    fn transmittance() {
+        // the expected weight of passing through is exp(-sigma_t * d) for every channel
+        let m = Medium {
+            sigma_a: Vct::new(0.1, 0.5, 1.0),
+            sigma_s: Vct::new(0.2, 0.0, 0.0),
+            g: 0.0,
+            grid: None,
+        };
+        let r = Ray::new(Vct::zero(), Vct::new(0.0, 0.0, 1.0));
+        let mut rng = Rng::new(7);
+        let n = 200000;
+        let mut sum = Vct::zero();
+        for _ in 0..n {
+            if let Interaction::Pass(w) = m.sample(&r, 1.0, None, &mut rng) {
+                sum += w;
+            }
+        }
+        let tr = sum / n as Flt;
+        for d in 0..3 {
+            let expect = (-(m.sigma_a[d] + m.sigma_s[d])).exp();
+            assert!((tr[d] - expect).abs() < 0.01, "{} {}", tr[d], expect);
+        }
+    }
+
+    #[test]
+    fn phase() {
+        // the phase function integrates to one over the sphere and leans forward with g
+        let m = Medium { sigma_a: Vct::zero(), sigma_s: Vct::one(), g: 0.6, grid: None };
+        let n = 10000;
+        let sum = (0..n)
+            .map(|i| m.phase_value(-1.0 + 2.0 * (i as Flt + 0.5) / n as Flt) * 2.0 * PI * 2.0)
+            .sum::<Flt>();
+        assert!((sum / n as Flt - 1.0).abs() < 1e-3);
+        let d = Vct::new(0.0, 0.0, 1.0);
+        let mut rng = Rng::new(3);
+        let mean = (0..n).map(|_| m.phase(d, rng.gen(), rng.gen()).z).sum::<Flt>() / n as Flt;
+        assert!((mean - m.g).abs() < 0.02);
+    }
+
+    #[test]
+    fn subsurface() {
+        let m = Medium::subsurface(Vct::new(0.0, 0.5, 0.99), Vct::new(1.0, 2.0, 4.0));
+        let sigma_t = m.sigma_a + m.sigma_s;
+        assert!((sigma_t - Vct::new(1.0, 0.5, 0.25)).len() < 1e-9);
+        // brighter colors need more single scattering, always below 1
+        assert!(m.sigma_s.x.abs() < 1e-3);
+        assert!(m.sigma_s.y / sigma_t.y < m.sigma_s.z / sigma_t.z);
+        assert!(m.sigma_s.z < sigma_t.z);
+    }
+}
@@ -1,6 +1,24 @@
 mod sppm;
 pub mod world;
+pub mod aov;
 pub mod camera;
+pub mod film;
+pub mod filter;
+pub mod progressive;
+pub mod tile;
+pub mod medium;
+pub mod light;
+pub mod ies;
+pub mod sky;
 
+pub use aov::{AovFilm, AovPass, AovPixel, AovSample, Aovs};
 pub use camera::Camera;
-pub use world::{Renderer, World, PT, SPPM};
\ No newline at end of file
+pub use film::{Film, Stat};
+pub use medium::{Grid, Interaction, Medium};
+pub use light::Light;
+pub use ies::Ies;
+pub use sky::Sky;
+pub use filter::Filter;
+pub use progressive::{Checkpoint, Progressive};
+pub use tile::{Crop, TileOrder, Tiles};
+pub use world::{Adaptive, Renderer, World, PT, SPPM};
\ No newline at end of file
@@ -0,0 +1,87 @@
+use super::{AovFilm, Film, Stat};
+use crate::{linalg::Vct, utils::Image, Deserialize, Flt, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+fn one() -> usize {
+    1
+}
+
+// Snapshot and checkpoint settings shared by the renderers. Intervals are counted in
+// passes for PT and in rounds for SPPM, "{}" in snapshot is replaced by the pass number.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Progressive {
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    #[serde(default = "one")]
+    pub snapshot_interval: usize,
+    #[serde(default)]
+    pub checkpoint: Option<String>,
+    #[serde(default = "one")]
+    pub checkpoint_interval: usize,
+    #[serde(default)]
+    pub resume: bool,
+}
+
+// Everything needed to continue a render. The samplers are restarted from (pixel, sample
+// index) and the photon passes are seeded by the round, so the sample counts and the round
+// are the whole random state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Checkpoint {
+    PT {
+        w: usize,
+        h: usize,
+        pass: usize,
+        film: Film,
+        stats: Vec<Stat>,
+        #[serde(default)]
+        aov: Option<AovFilm>,
+    },
+    SPPM {
+        w: usize,
+        h: usize,
+        round: usize,
+        radius: Flt,
+        pixel: Vec<Vct>,
+    },
+}
+
+impl Progressive {
+    pub fn snapshot(&self, pass: usize, p: &Image) {
+        if let Some(path) = &self.snapshot {
+            if pass.is_multiple_of(self.snapshot_interval.max(1)) {
+                p.save_png(&path.replace("{}", &pass.to_string()));
+            }
+        }
+    }
+
+    pub fn need_checkpoint(&self, pass: usize) -> bool {
+        self.checkpoint.is_some() && pass.is_multiple_of(self.checkpoint_interval.max(1))
+    }
+
+    pub fn save(&self, ckpt: &Checkpoint) {
+        if let Some(path) = &self.checkpoint {
+            println!("Saving checkpoint to {}", path);
+            let tmp = format!("{}.tmp", path);
+            let file =
+                File::create(&tmp).unwrap_or_else(|e| panic!("Cannot create {}: {}", tmp, e));
+            serde_json::to_writer(BufWriter::new(file), ckpt).expect("Cannot save checkpoint");
+            fs::rename(&tmp, path).unwrap_or_else(|e| panic!("Cannot write {}: {}", path, e));
+            println!("...done");
+        }
+    }
+
+    pub fn load(&self) -> Option<Checkpoint> {
+        match &self.checkpoint {
+            Some(path) if self.resume && Path::new(path).exists() => {
+                println!("Resuming from {}", path);
+                let file =
+                    File::open(path).unwrap_or_else(|e| panic!("Cannot open {}: {}", path, e));
+                Some(serde_json::from_reader(BufReader::new(file)).expect("Invalid checkpoint"))
+            },
+            _ => None,
+        }
+    }
+}
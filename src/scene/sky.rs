@@ -0,0 +1,212 @@
+use super::Light;
+use crate::{
+    linalg::{Transform, TransformType, Vct},
+    utils::spectrum::xyz_to_rgb,
+    Deserialize, Flt, Serialize, EPS, PI,
+};
+use std::sync::OnceLock;
+
+fn default_turbidity() -> Flt {
+    3.0
+}
+
+fn default_ground_albedo() -> Vct {
+    Vct::new(0.3, 0.3, 0.3)
+}
+
+fn default_scale() -> Flt {
+    1.0
+}
+
+fn default_sun() -> bool {
+    true
+}
+
+fn default_diameter() -> Flt {
+    0.53
+}
+
+// the illuminance of the sun above the atmosphere, in klux
+const SOLAR: Flt = 127.5;
+
+// the wavelengths of red, green and blue in micrometers, for the attenuation of the sun
+const WAVELENGTHS: [Flt; 3] = [0.61, 0.55, 0.465];
+
+// The clear sky of Preetham et al., lighting the rays leaving the scene. The sun is at
+// elevation above the horizon and at azimuth around y, from z towards x, both in degrees.
+// Radiances are in kcd/m^2 and irradiances in klux, times scale. Below the horizon is a
+// diffuse ground. The disk of the sun is not part of the radiance, it is a directional light.
+// Only PT sees the sky, SPPM refuses scenes with one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sky {
+    pub elevation: Flt,
+    pub azimuth: Flt,
+    #[serde(default = "default_turbidity")]
+    pub turbidity: Flt, // 2 for a very clear sky to 10 for a hazy one
+    #[serde(default = "default_ground_albedo")]
+    pub ground_albedo: Vct,
+    #[serde(default = "default_scale")]
+    pub scale: Flt,
+    #[serde(default = "default_sun")]
+    pub sun: bool, // add the sun to the lights
+    #[serde(default = "default_diameter")]
+    pub diameter: Flt, // of the sun in degrees
+    #[serde(skip)]
+    ground: OnceLock<Vct>, // the radiance of the ground
+}
+
+// the coefficients of the Perez function for Y, x and y
+fn perez(t: Flt) -> [[Flt; 5]; 3] {
+    [
+        [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ],
+        [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ],
+        [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ],
+    ]
+}
+
+// the Perez function at a direction of cosine cos with the zenith and angle gamma to the sun
+fn f(c: &[Flt; 5], cos: Flt, gamma: Flt) -> Flt {
+    let g = gamma.cos();
+    (1.0 + c[0] * (c[1] / cos).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * g * g)
+}
+
+// Y, x and y at the zenith when the sun is theta from it
+fn zenith(t: Flt, theta: Flt) -> [Flt; 3] {
+    let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+    let (t2, th2, th3) = (t * t, theta * theta, theta * theta * theta);
+    [
+        (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192,
+        t2 * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * theta)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * theta + 0.25886),
+        t2 * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * theta)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * theta + 0.26688),
+    ]
+}
+
+impl Sky {
+    pub fn new(elevation: Flt, azimuth: Flt, turbidity: Flt) -> Self {
+        Self {
+            elevation,
+            azimuth,
+            turbidity,
+            ground_albedo: default_ground_albedo(),
+            scale: default_scale(),
+            sun: default_sun(),
+            diameter: default_diameter(),
+            ground: OnceLock::new(),
+        }
+    }
+
+    // towards the sun
+    pub fn dir(&self) -> Vct {
+        let (el, az) = (self.elevation.to_radians(), self.azimuth.to_radians());
+        Vct::new(el.cos() * az.sin(), el.sin(), el.cos() * az.cos())
+    }
+
+    // the radiance coming from direction d
+    pub fn radiance(&self, d: Vct) -> Vct {
+        let d = d.norm();
+        if d.y < 0.0 {
+            return self.ground();
+        }
+        let theta = PI * 0.5 - self.elevation.max(0.0).to_radians();
+        let gamma = d.dot(self.dir()).clamp(-1.0, 1.0).acos();
+        let (c, z) = (perez(self.turbidity), zenith(self.turbidity, theta));
+        let [y, cx, cy] =
+            [0, 1, 2].map(|i| z[i] * f(&c[i], d.y.max(EPS), gamma) / f(&c[i], 1.0, theta));
+        let xyz = Vct::new(cx / cy * y, y, (1.0 - cx - cy) / cy * y);
+        xyz_to_rgb(xyz).max(Vct::zero()) * self.scale
+    }
+
+    // the irradiance of the sun on a surface facing it, reddened through the air
+    pub fn sun_irradiance(&self) -> Vct {
+        if self.elevation <= 0.0 {
+            return Vct::zero();
+        }
+        // the relative air mass of Kasten and Young, and the Angstrom turbidity
+        let z = 90.0 - self.elevation;
+        let m = 1.0 / (z.to_radians().cos() + 0.50572 * (96.07995 - z).powf(-1.6364));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let t = |l: Flt| (-m * (0.008735 * l.powf(-4.08) + beta * l.powf(-1.3))).exp();
+        Vct::new(t(WAVELENGTHS[0]), t(WAVELENGTHS[1]), t(WAVELENGTHS[2])) * (SOLAR * self.scale)
+    }
+
+    // the ground is lit by the sky above it and the sun
+    fn ground(&self) -> Vct {
+        *self.ground.get_or_init(|| {
+            let (n, m) = (32, 64);
+            let mut e = Vct::zero();
+            for i in 0..n {
+                let cos = (i as Flt + 0.5) / n as Flt;
+                let sin = (1.0 - cos * cos).sqrt();
+                for j in 0..m {
+                    let phi = 2.0 * PI * (j as Flt + 0.5) / m as Flt;
+                    e += self.radiance(Vct::new(sin * phi.cos(), cos, sin * phi.sin())) * cos;
+                }
+            }
+            let e = e * (2.0 * PI / (n * m) as Flt)
+                + self.sun_irradiance() * self.elevation.max(0.0).to_radians().sin();
+            self.ground_albedo * e / PI
+        })
+    }
+
+    // the sun as a light shining along -dir, if it is above the horizon
+    pub fn sun(&self) -> Option<Light> {
+        if !self.sun || self.elevation <= 0.0 {
+            return None;
+        }
+        let transform = Transform::new(vec![
+            TransformType::Rotate { axis: String::from("x"), degree: self.elevation },
+            TransformType::Rotate { axis: String::from("y"), degree: self.azimuth + 180.0 },
+        ]);
+        let irradiance = self.sun_irradiance();
+        Some(Light::Directional { transform, irradiance, diameter: self.diameter })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sky() {
+        let sky = Sky::new(30.0, 60.0, 3.0);
+        match sky.sun() {
+            Some(Light::Directional { transform, .. }) => {
+                assert!((transform.z().norm() + sky.dir()).len() < 1e-9)
+            },
+            _ => panic!("No sun"),
+        }
+        // brighter around the sun than away from it, and positive everywhere
+        let d = sky.dir();
+        let near = sky.radiance(Vct::new(d.x, d.y + 0.1, d.z));
+        let away = sky.radiance(Vct::new(-d.x, d.y + 0.1, -d.z));
+        assert!(near.y > away.y && away.x > 0.0 && away.z > away.x);
+        assert!(sky.radiance(Vct::new(0.0, -1.0, 0.0)).y > 0.0);
+        // a low sun is weaker and redder
+        let (high, low) = (sky.sun_irradiance(), Sky::new(5.0, 60.0, 3.0).sun_irradiance());
+        assert!(high.y > 50.0 && high.y < 110.0 && low.y < high.y);
+        assert!(low.x / low.z > high.x / high.z);
+        assert!(Sky::new(-5.0, 0.0, 3.0).sun().is_none());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{Deserialize, Flt, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+fn default_size() -> usize {
+    16
+}
+
+fn default_order() -> TileOrder {
+    TileOrder::Scanline
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Tiles {
+    #[serde(default = "default_size")]
+    pub size: usize,
+    #[serde(default = "default_order")]
+    pub order: TileOrder,
+}
+
+impl Default for Tiles {
+    fn default() -> Self {
+        Self { size: default_size(), order: default_order() }
+    }
+}
+
+// [x0, x1) x [y0, y1) in pixels of the output image, i.e. y goes down
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Crop {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Crop {
+    // the window in camera coordinates (y goes up), clipped to an image of size (w, h)
+    pub fn window(&self, w: usize, h: usize) -> (usize, usize, usize, usize) {
+        let (x0, x1) = (self.x0.min(w), self.x1.min(w));
+        let (y0, y1) = (self.y0.min(h), self.y1.min(h));
+        (x0, h - y1.max(y0), x1.max(x0), h - y0)
+    }
+}
+
+// d of (x, y) along a Hilbert curve filling a n x n grid, n is a power of 2
+fn hilbert(n: usize, mut x: usize, mut y: usize) -> usize {
+    let (mut d, mut s) = (0, n / 2);
+    while s > 0 {
+        let rx = (x & s > 0) as usize;
+        let ry = (y & s > 0) as usize;
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x &= s - 1;
+        y &= s - 1;
+        s /= 2;
+    }
+    d
+}
+
+impl Tiles {
+    // split [x0, x1) x [y0, y1) into tiles, returned in the order they should be rendered
+    pub fn split(
+        &self,
+        x0: usize,
+        y0: usize,
+        x1: usize,
+        y1: usize,
+    ) -> Vec<(usize, usize, usize, usize)> {
+        let size = self.size.max(1);
+        let (nx, ny) = ((x1 - x0).div_ceil(size), (y1 - y0).div_ceil(size));
+        let mut id: Vec<(usize, usize)> = Vec::new();
+        (0..ny).rev().for_each(|y| (0..nx).for_each(|x| id.push((x, y))));
+        match self.order {
+            TileOrder::Scanline => (),
+            TileOrder::Spiral => {
+                let (cx, cy) = ((nx as Flt - 1.0) / 2.0, (ny as Flt - 1.0) / 2.0);
+                let key = |&(x, y): &(usize, usize)| {
+                    let (dx, dy) = (x as Flt - cx, y as Flt - cy);
+                    let ring = dx.abs().max(dy.abs()).round();
+                    (ring, dy.atan2(dx))
+                };
+                id.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            },
+            TileOrder::Hilbert => {
+                let n = nx.max(ny).next_power_of_two();
+                id.sort_by_key(|&(x, y)| hilbert(n, x, y));
+            },
+        }
+        id.into_iter()
+            .map(|(x, y)| {
+                let (tx, ty) = (x0 + x * size, y0 + y * size);
+                (tx, ty, (tx + size).min(x1), (ty + size).min(y1))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        for &order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert].iter() {
+            let tiles = Tiles { size: 16, order }.split(3, 5, 103, 58);
+            assert_eq!(tiles.len(), 7 * 4);
+            let area: usize = tiles.iter().map(|t| (t.2 - t.0) * (t.3 - t.1)).sum();
+            assert_eq!(area, 100 * 53);
+        }
+        let hilbert: Vec<_> =
+            (0..4).map(|d| (0..4).find(|&i| hilbert(2, i % 2, i / 2) == d)).collect();
+        assert_eq!(hilbert, vec![Some(0), Some(2), Some(3), Some(1)]);
+    }
+}
@@ -1,25 +1,53 @@
 use super::{
     sppm::{Counter, KDTree, Pixel, Point},
-    Camera,
+    AovFilm, AovPass, AovPixel, AovSample, Aovs, Camera, Checkpoint, Crop, Film, Filter,
+    Ies, Interaction, Light, Medium, Progressive, Sky, Stat, Tiles,
 };
 use crate::{
-    geo::Material,
-    geo::{Geo, HitResult},
-    linalg::{Ray, Vct},
-    utils::{clamp, Image, Rng},
+    geo::{Dispersion, Geo, HitResult, Material, TextureRaw},
+    linalg::{Ray, Transform, Vct},
+    utils::spectrum::{sample_wavelengths, spectral, to_rgb},
+    utils::{luminance, Alias, Denoise, Guide, Image, Rng, Sampler, SamplerType},
     Deserialize, Flt, Serialize, EPS, PI,
 };
 
 use pbr::ProgressBar;
 use rand::prelude::*;
 use rayon::prelude::*;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
 use std::time;
 use std::time::Duration;
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PT {
     pub sample: usize,
+    #[serde(default)]
+    pub pass_sample: usize, // samples added to every pixel per pass, 0 means all at once
+    #[serde(default)]
+    pub adaptive: Option<Adaptive>,
+    #[serde(default)]
+    pub time_limit: Option<Flt>, // in seconds, keep adding passes until it is spent
+    #[serde(default)]
+    pub tile: Tiles,
+    // render these pixels from scratch, the others keep what the checkpoint has or stay black
+    #[serde(default)]
+    pub crop: Option<Crop>,
+    #[serde(default)]
+    pub spectral: bool, // trace wavelengths instead of RGB, needed for dispersion
+    #[serde(default)]
+    pub emitters: bool, // sample the emissive objects for direct light as well as the lights
+}
+
+// keep rendering passes of pass_sample samples on the pixels whose relative
+// error is still above threshold, until they reach max_sample
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Adaptive {
+    pub threshold: Flt,
+    pub pass_sample: usize,
+    pub max_sample: usize,
+    #[serde(default)]
+    pub heatmap: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -31,6 +59,64 @@ pub struct SPPM {
     pub rounds: usize,
     pub light_pos: Vct,
     pub light_r: Flt,
+    #[serde(default)]
+    pub time_limit: Option<Flt>, // in seconds, keep adding rounds until it is spent
+    #[serde(default)]
+    pub emitters: bool, // emit photons from the emissive objects as well as from the lights
+}
+
+// where photons and direct light come from, the disk at light_pos is only used when there
+// are none
+#[derive(Copy, Clone, Debug)]
+enum Source {
+    Object(usize),
+    Light(usize),
+}
+
+// The sources with the luminance of their power, picked in proportion to it. Directional
+// lights cover the sphere bound around the scene.
+struct Lighting {
+    sources: Vec<(Source, Flt)>,
+    table: Alias,
+    sampled: Vec<bool>, // for every object, whether it is among the sources
+    bound: (Vct, Flt),
+}
+
+impl Lighting {
+    fn new(mut sources: Vec<(Source, Flt)>, objs: usize, bound: (Vct, Flt)) -> Self {
+        sources.retain(|s| s.1 > 0.0);
+        let table = Alias::new(&sources.iter().map(|s| s.1).collect::<Vec<_>>());
+        let mut sampled = vec![false; objs];
+        for s in sources.iter() {
+            if let Source::Object(i) = s.0 {
+                sampled[i] = true;
+            }
+        }
+        Self { sources, table, sampled, bound }
+    }
+
+    // a source and the probability to pick it
+    fn pick(&self, u: Flt) -> (Source, Flt) {
+        let (i, p) = self.table.sample(u);
+        (self.sources[i].0, p)
+    }
+}
+
+// the rays a path continues with at a hit and their weights
+enum Scatter {
+    None,
+    One(Ray, Flt),
+    Two(Ray, Flt, Ray, Flt),
+}
+
+impl Scatter {
+    fn sum(self, mut f: impl FnMut(&Ray, Flt) -> Vct) -> Vct {
+        match self {
+            Scatter::None => Vct::zero(),
+            Scatter::One(r, w) => f(&r, w),
+            Scatter::Two(a, wa, b, wb) => f(&a, wa) + f(&b, wb),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -40,16 +126,35 @@ pub enum Renderer {
     SPPM(SPPM),
 }
 
+// the longest random walk inside a subsurface object
+const MAX_WALK: usize = 256;
+
+// the samples estimating the power of an emissive object
+const LIGHT_PROBE: usize = 256;
+
 pub struct World {
     pub objs: Vec<Box<dyn Geo>>,
     pub camera: Camera,
     pub max_depth: usize,
     pub thread_num: usize,
     pub stack_size: usize,
+    pub na: Flt,
     pub n1: Flt,
     pub n2: Flt,
     pub r0: Flt,
     pub renderer: Renderer,
+    pub sampler: SamplerType,
+    pub filter: Filter,
+    pub progressive: Progressive,
+    pub aovs: Option<Aovs>,
+    pub denoise: Option<Denoise>,
+    pub media: Vec<Medium>,
+    pub medium: Option<usize>,        // the medium filling the scene
+    pub interior: Vec<Option<usize>>, // the medium inside every object
+    pub profiles: Vec<Option<(Ies, Transform)>>, // modulating the emission of every object
+    pub lights: Vec<Light>,
+    pub sky: Option<Sky>, // the light of the rays leaving the scene, in PT only
+    lighting: OnceLock<Lighting>, // for the direct light of PT, built on first use
 }
 
 impl World {
@@ -68,119 +173,610 @@ impl World {
             max_depth,
             thread_num,
             stack_size,
+            na,
             n1: na / ng,
             n2: ng / na,
             r0: ((na - ng) * (na - ng)) / ((na + ng) * (na + ng)),
             renderer,
+            sampler: SamplerType::Independent,
+            filter: Filter::default(),
+            progressive: Progressive::default(),
+            aovs: None,
+            denoise: None,
+            media: Vec::new(),
+            medium: None,
+            interior: Vec::new(),
+            profiles: Vec::new(),
+            lights: Vec::new(),
+            sky: None,
+            lighting: OnceLock::new(),
         }
     }
 
     pub fn render(&self, p: &mut Image) {
         match self.renderer {
-            Renderer::PT(cfg) => self.path_tracing(p, cfg),
+            Renderer::PT(ref cfg) => self.path_tracing(p, cfg),
             Renderer::SPPM(cfg) => self.stochastic_progressive_photon_mapping(p, cfg),
         };
     }
 
-    fn gen(rng: &mut Rng) -> Flt {
-        let r = 2.0 * rng.gen();
-        if r < 1.0 {
-            r.sqrt() - 1.0
-        } else {
-            1.0 - (2.0 - r).sqrt()
-        }
-    }
-
     pub fn add(&mut self, obj: Box<dyn Geo>) -> &mut Self {
         self.objs.push(obj);
+        self.interior.push(None);
+        self.profiles.push(None);
+        self
+    }
+
+    // modulate the emission of the last object added by ies, oriented by transform
+    pub fn set_profile(&mut self, ies: Ies, transform: Transform) -> &mut Self {
+        let i = self.profiles.len() - 1;
+        self.profiles[i] = Some((ies, transform));
+        self
+    }
+
+    // the part of the emission of object id going along d
+    fn profile(&self, id: usize, d: Vct) -> Flt {
+        self.profiles[id].as_ref().map_or(1.0, |(ies, t)| ies.eval(t, d))
+    }
+
+    // fill the inside of the last object added with m, the object should be closed
+    pub fn set_interior(&mut self, m: Medium) -> &mut Self {
+        self.media.push(m);
+        let (i, m) = (self.interior.len() - 1, self.media.len() - 1);
+        self.interior[i] = Some(m);
         self
     }
 
-    fn find<'a>(&'a self, r: &Ray) -> Option<HitResult> {
+    pub fn set_medium(&mut self, m: Medium) -> &mut Self {
+        self.media.push(m);
+        self.medium = Some(self.media.len() - 1);
+        self
+    }
+
+    fn denoise(&self, p: &mut Image, guide: &Guide) {
+        if let Some(cfg) = &self.denoise {
+            if let Some(path) = &cfg.noisy {
+                p.save_png(path);
+            }
+            println!("Denoising...");
+            *p = p.denoise(guide, cfg);
+            println!("...done");
+        }
+    }
+
+    // the index of the closest object hit by r and the hit
+    fn find_obj(&self, r: &Ray) -> Option<(usize, HitResult)> {
         let mut t: Flt = 1e30;
         let mut obj = None;
         let mut gg = None;
-        self.objs.iter().for_each(|o| {
+        self.objs.iter().enumerate().for_each(|(i, o)| {
             if let Some(d) = o.hit_t(r) {
                 if d.0 < t {
                     t = d.0;
                     gg = d.1;
-                    obj = Some(o);
+                    obj = Some(i);
                 }
             }
         });
-        if let Some(o) = obj {
-            return Some(o.hit(r, (t, gg)));
+        obj.map(|i| (i, self.objs[i].hit(r, (t, gg))))
+    }
+
+    fn find(&self, r: &Ray) -> Option<HitResult> {
+        self.find_obj(r).map(|(_, hit)| hit)
+    }
+
+    // either side of a surface with normal n
+    fn side(n: Vct, rng: &mut dyn Sampler) -> Vct {
+        if rng.gen() < 0.5 {
+            n
+        } else {
+            -n
+        }
+    }
+
+    // a cosine weighted direction around w
+    fn cosine(w: Vct, rng: &mut dyn Sampler) -> Vct {
+        let (r1, r2) = (PI * 2.0 * rng.gen(), rng.gen());
+        let r2s = r2.sqrt();
+        let u = (if w.x.abs() <= 0.1 {
+            Vct::new(1.0, 0.0, 0.0)
+        } else {
+            Vct::new(0.0, 1.0, 0.0)
+        } % w)
+            .norm();
+        let v = w % u;
+        ((u * r1.cos() + v * r1.sin()) * r2s + w * (1.0 - r2).sqrt()).norm()
+    }
+
+    // Random walk inside object id, which r hits from outside. The walk goes in and out
+    // through diffuse transmission, returns the ray leaving the object, the normal where it
+    // leaves and the weight, or None when absorbed.
+    fn subsurface(
+        &self,
+        id: usize,
+        hit: &HitResult,
+        r: &Ray,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> Option<(Ray, Vct, Vct)> {
+        let obj = &self.objs[id];
+        let (color, mean_free_path) = (hit.texture.color, hit.texture.mean_free_path);
+        let medium = Medium::subsurface(spectral(color, lambda), spectral(mean_free_path, lambda));
+        let inside = if hit.norm.dot(r.direct) < 0.0 { -hit.norm } else { hit.norm };
+        let mut ray = Ray::new(hit.pos, Self::cosine(inside, rng));
+        let mut weight = Vct::one();
+        for _ in 0..MAX_WALK {
+            let tmp = obj.hit_t(&ray)?;
+            match medium.sample_path(&ray, tmp.0, weight, None, rng) {
+                Interaction::Pass(w) => {
+                    let out = obj.hit(&ray, tmp);
+                    let n = if out.norm.dot(ray.direct) > 0.0 { out.norm } else { -out.norm };
+                    let s = Ray::new(out.pos, Self::cosine(n, rng));
+                    return Some((s, out.norm, weight * w));
+                },
+                Interaction::Scatter(s, w) => {
+                    ray = s;
+                    weight *= w;
+                },
+                Interaction::Absorb => return None,
+            }
         }
         None
     }
 
-    fn pt(&self, r: &Ray, mut depth: usize, rng: &mut Rng) -> Vct {
-        if let Some(HitResult { pos, norm, ref texture }) = self.find(r) {
-            depth += 1;
-            if depth > self.max_depth {
-                return texture.emission;
+    // The plain material a mix or a coated hit behaves as this time. A coat reflects like a
+    // mirror with its Fresnel reflectance, otherwise the base is dimmed once more by it for
+    // the light leaving through the coat. Layers don't nest deeper than a coat over a mix, the
+    // scene refuses the others.
+    fn layer(&self, mut hit: HitResult, r: &Ray, rng: &mut dyn Sampler) -> HitResult {
+        let cos = hit.norm.dot(r.direct).abs();
+        let t = &mut hit.texture;
+        let mut scale = 1.0;
+        if t.material == Material::Coated {
+            let coat = t.coat.expect("A coated material needs a coat");
+            let r0 = ((self.na - coat.ior) * (self.na - coat.ior))
+                / ((self.na + coat.ior) * (self.na + coat.ior));
+            let re = r0 + (1.0 - r0) * (1.0 - cos).powi(5);
+            if rng.gen() < re {
+                t.material = Material::Specular;
+                t.color = Vct::one();
+                return hit;
             }
-            let mut color = texture.color;
-            if depth > 5 {
-                let p = color.x.max(color.y.max(color.z));
-                if rng.gen() < p {
-                    color /= p;
-                } else {
-                    return texture.emission;
-                }
+            t.material = coat.base.material;
+            t.color = coat.base.color.unwrap_or(t.color);
+            scale = 1.0 - re;
+        }
+        if t.material == Material::Mix {
+            let mix = t.mix.expect("A mix material needs a mix");
+            let weight = mix.weight.unwrap_or_else(|| luminance(t.color));
+            let layer = if rng.gen() < weight { mix.second } else { mix.first };
+            t.material = layer.material;
+            t.color = layer.color.unwrap_or(t.color);
+        }
+        t.color *= scale;
+        hit
+    }
+
+    // What a path does at a hit, depth counts this hit: the light emitted there,
+    // the color filtering the scattered light, and the rays to follow.
+    fn shade(
+        &self,
+        r: &Ray,
+        id: usize,
+        hit: &HitResult,
+        depth: usize,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> (Vct, Vct, Scatter) {
+        let HitResult { pos, norm, ref texture } = *hit;
+        let emission = spectral(texture.emission * self.profile(id, -r.direct), lambda);
+        if depth > self.max_depth {
+            return (emission, Vct::zero(), Scatter::None);
+        }
+        if texture.material == Material::Null {
+            return (emission, Vct::one(), Scatter::One(Ray::new(pos, r.direct), 1.0));
+        }
+        if texture.material == Material::Subsurface {
+            return match self.subsurface(id, hit, r, lambda, rng) {
+                Some((s, _, w)) => (emission, w, Scatter::One(s, 1.0)),
+                None => (emission, Vct::zero(), Scatter::None),
+            };
+        }
+        let mut color = spectral(texture.color, lambda);
+        if depth > 5 {
+            let p = color.x.max(color.y.max(color.z));
+            if rng.gen() < p {
+                color /= p;
+            } else {
+                return (emission, Vct::zero(), Scatter::None);
             }
-            let mut ff = || {
-                let nd = norm.dot(r.direct);
-                if texture.material == Material::Diffuse {
-                    let w = if nd < 0.0 { norm } else { -norm };
-                    let (r1, r2) = (PI * 2.0 * rng.gen(), rng.gen());
-                    let r2s = r2.sqrt();
-                    let u = (if w.x.abs() <= 0.1 {
-                        Vct::new(1.0, 0.0, 0.0)
-                    } else {
-                        Vct::new(0.0, 1.0, 0.0)
-                    } % w)
-                        .norm();
-                    let v = w % u;
-                    let d = (u * r1.cos() + v * r1.sin()) * r2s + w * (1.0 - r2).sqrt();
-                    return self.pt(&Ray::new(pos, d.norm()), depth, rng);
-                }
-                let refl = Ray::new(pos, r.direct - norm * (2.0 * nd));
-                if texture.material == Material::Specular {
-                    return self.pt(&refl, depth, rng);
-                }
-                let w = if nd < 0.0 { norm } else { -norm };
-                let (it, ddw) = (norm.dot(w) > 0.0, r.direct.dot(w));
-                let (n, sign) = if it { (self.n1, 1.0) } else { (self.n2, -1.0) };
-                let cos2t = 1.0 - n * n * (1.0 - ddw * ddw);
-                if cos2t < 0.0 {
-                    return self.pt(&refl, depth, rng);
-                }
-                let td = (r.direct * n - norm * ((ddw * n + cos2t.sqrt()) * sign)).norm();
-                let refr = Ray::new(pos, td);
-                let c = if it { 1.0 + ddw } else { 1.0 - td.dot(norm) };
-                let cc = c * c;
-                let re = self.r0 + (1.0 - self.r0) * cc * cc * c;
-                let tr = 1.0 - re;
-                if depth > 2 {
-                    let p = 0.25 + 0.5 * re;
-                    if rng.gen() < p {
-                        self.pt(&refl, depth, rng) * (re / p)
-                    } else {
-                        self.pt(&refr, depth, rng) * (tr / (1.0 - p))
+        }
+        let nd = norm.dot(r.direct);
+        if texture.material == Material::Diffuse {
+            let w = if nd < 0.0 { norm } else { -norm };
+            let d = Self::cosine(w, rng);
+            return (emission, color, Scatter::One(Ray::new(pos, d), 1.0));
+        }
+        let refl = Ray::new(pos, r.direct - norm * (2.0 * nd));
+        if texture.material == Material::Specular {
+            return (emission, color, Scatter::One(refl, 1.0));
+        }
+        let w = if nd < 0.0 { norm } else { -norm };
+        let (it, ddw) = (norm.dot(w) > 0.0, r.direct.dot(w));
+        let (n1, n2, r0) = self.ior(texture, lambda);
+        if let (Some(_), Some(l)) = (texture.dispersion, lambda) {
+            if l.y != l.x {
+                color = Vct::new(color.x * 3.0, 0.0, 0.0);
+            }
+        }
+        let (n, sign) = if it { (n1, 1.0) } else { (n2, -1.0) };
+        let cos2t = 1.0 - n * n * (1.0 - ddw * ddw);
+        if cos2t < 0.0 {
+            return (emission, color, Scatter::One(refl, 1.0));
+        }
+        let td = (r.direct * n - norm * ((ddw * n + cos2t.sqrt()) * sign)).norm();
+        let refr = Ray::new(pos, td);
+        let c = if it { 1.0 + ddw } else { 1.0 - td.dot(norm) };
+        let cc = c * c;
+        let re = r0 + (1.0 - r0) * cc * cc * c;
+        let tr = 1.0 - re;
+        let scatter = if depth > 2 {
+            let p = 0.25 + 0.5 * re;
+            if rng.gen() < p {
+                Scatter::One(refl, re / p)
+            } else {
+                Scatter::One(refr, tr / (1.0 - p))
+            }
+        } else {
+            Scatter::Two(refl, re, refr, tr)
+        };
+        (emission, color, scatter)
+    }
+
+    // The relative indices of refraction going into and out of a refractive object and its
+    // reflectance at normal incidence. Dispersive objects use the hero wavelength, or the D
+    // line outside the spectral mode.
+    fn ior(&self, texture: &TextureRaw, lambda: Option<Vct>) -> (Flt, Flt, Flt) {
+        match texture.dispersion {
+            Some(d) => {
+                let (na, ng) = (self.na, d.ior(lambda.map_or(Dispersion::D_LINE, |l| l.x)));
+                (na / ng, ng / na, ((na - ng) * (na - ng)) / ((na + ng) * (na + ng)))
+            },
+            None => (self.n1, self.n2, self.r0),
+        }
+    }
+
+    // The other wavelengths would bend differently through a dispersive surface, only the
+    // hero one goes on, repeated to tell that the others are gone.
+    fn hero(texture: &TextureRaw, lambda: Option<Vct>) -> Option<Vct> {
+        match (texture.material, texture.dispersion, lambda) {
+            (Material::Refractive, Some(_), Some(l)) => Some(Vct::new(l.x, l.x, l.x)),
+            _ => lambda,
+        }
+    }
+
+    // the medium s is in, s leaving the hit of r on object id while r was in medium
+    fn next_medium(
+        &self,
+        id: usize,
+        hit: &HitResult,
+        r: &Ray,
+        s: &Ray,
+        medium: Option<usize>,
+    ) -> Option<usize> {
+        let (a, b) = (hit.norm.dot(r.direct), hit.norm.dot(s.direct));
+        // a random walk comes out on the side it went in
+        if a * b <= 0.0 || hit.texture.material == Material::Subsurface {
+            return medium;
+        }
+        // going through the surface, the interiors are not nested
+        if a < 0.0 {
+            self.interior[id]
+        } else {
+            self.medium
+        }
+    }
+
+    // Beer-Lambert attenuation of s leaving a hit on object id when s goes into it, up to
+    // where s comes out of the object again
+    fn absorb(&self, id: usize, norm: Vct, absorption: Vct, s: &Ray) -> Vct {
+        if absorption == Vct::zero() || norm.dot(s.direct) >= 0.0 {
+            return Vct::one();
+        }
+        match self.objs[id].hit_t(s) {
+            Some((t, _)) => {
+                let a = absorption * -(t * s.direct.len());
+                Vct::new(a.x.exp(), a.y.exp(), a.z.exp())
+            },
+            None => Vct::one(),
+        }
+    }
+
+    // The part of the light going from r.origin up to t_max, r is in medium. Objects of null
+    // material are seen through, the media behind them are not accounted for.
+    fn transmittance(
+        &self,
+        r: &Ray,
+        t_max: Flt,
+        medium: Option<usize>,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> Vct {
+        let mut w = Vct::one();
+        if let Some(m) = medium {
+            match self.media[m].sample(r, t_max, lambda, rng) {
+                Interaction::Pass(t) => w = t,
+                _ => return Vct::zero(),
+            }
+        }
+        let (mut s, mut left) = (*r, t_max);
+        while let Some(hit) = self.find(&s) {
+            let t = (hit.pos - s.origin).len();
+            if t >= left {
+                break;
+            }
+            if hit.texture.material != Material::Null {
+                return Vct::zero();
+            }
+            s = Ray::new(hit.pos, s.direct);
+            left -= t;
+        }
+        w
+    }
+
+    // A source picked by its power for the direct light at pos: the direction toward it, the
+    // distance to it, the light arriving from it and the probability of the pick.
+    fn sample_light(&self, pos: Vct, rng: &mut dyn Sampler) -> Option<(Vct, Flt, Vct, Flt)> {
+        let lighting = self.lighting();
+        if lighting.sources.is_empty() {
+            return None;
+        }
+        let (source, p) = lighting.pick(rng.gen());
+        let (d, dist, e) = match source {
+            Source::Light(i) => self.lights[i].sample(pos, rng),
+            Source::Object(i) => {
+                let (l, area) = self.objs[i].sample(rng)?;
+                let d = l.pos - pos;
+                let dist = d.len();
+                let d = d / dist;
+                let k = self.profile(i, -d) * l.norm.dot(d).abs() * area / (dist * dist);
+                // stop short of the emitter, which would shadow itself
+                (d, dist * (1.0 - 1e-5), l.texture.emission * k)
+            },
+        };
+        Some((d, dist, e, p))
+    }
+
+    // the light of the lights a white diffuse surface reflects at the hit of r, in medium
+    fn direct(
+        &self,
+        hit: &HitResult,
+        r: &Ray,
+        medium: Option<usize>,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> Vct {
+        if hit.texture.material != Material::Diffuse {
+            return Vct::zero();
+        }
+        let n = if hit.norm.dot(r.direct) < 0.0 { hit.norm } else { -hit.norm };
+        let (d, dist, e, p) = match self.sample_light(hit.pos, rng) {
+            Some(l) => l,
+            None => return Vct::zero(),
+        };
+        let cos = n.dot(d);
+        if cos <= 0.0 || e == Vct::zero() {
+            return Vct::zero();
+        }
+        let t = self.transmittance(&Ray::new(hit.pos, d), dist, medium, lambda, rng);
+        spectral(e, lambda) * t * (cos / (PI * p))
+    }
+
+    // the light of the lights medium scatters into -r.direct at pos
+    fn in_scatter(
+        &self,
+        pos: Vct,
+        r: &Ray,
+        medium: usize,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> Vct {
+        let (d, dist, e, p) = match self.sample_light(pos, rng) {
+            Some(l) => l,
+            None => return Vct::zero(),
+        };
+        if e == Vct::zero() {
+            return Vct::zero();
+        }
+        let phase = self.media[medium].phase_value(r.direct.dot(d));
+        let t = self.transmittance(&Ray::new(pos, d), dist, Some(medium), lambda, rng);
+        spectral(e, lambda) * t * (phase / p)
+    }
+
+    // The sources of the direct light. Emissive objects are among them if the renderer asks
+    // for them, the sphere around the points sampled on the objects bounds the scene.
+    fn lighting(&self) -> &Lighting {
+        self.lighting.get_or_init(|| {
+            let (emitters, bound) = self.emitters();
+            let mut sources = match self.renderer {
+                Renderer::PT(ref cfg) if cfg.emitters => emitters,
+                _ => Vec::new(),
+            };
+            sources.extend(
+                self.lights
+                    .iter()
+                    .enumerate()
+                    .map(|(i, l)| (Source::Light(i), luminance(l.power(bound.1)))),
+            );
+            Lighting::new(sources, self.objs.len(), bound)
+        })
+    }
+
+    // The light emitted by the surface r reaches, and the rest of the light arriving along r.
+    // Sampled tells that the direct light at the last hit already counted the emitters.
+    fn pt_split(
+        &self,
+        r: &Ray,
+        depth: usize,
+        sampled: bool,
+        medium: Option<usize>,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> (Vct, Vct) {
+        let hit = self.find_obj(r);
+        let mut weight = Vct::one();
+        if let Some(m) = medium {
+            let t_max = hit.as_ref().map_or(Flt::INFINITY, |(_, hit)| (hit.pos - r.origin).len());
+            match self.media[m].sample(r, t_max, lambda, rng) {
+                Interaction::Pass(w) => weight = w,
+                Interaction::Absorb => return (Vct::zero(), Vct::zero()),
+                Interaction::Scatter(s, mut w) => {
+                    if depth + 1 > self.max_depth {
+                        return (Vct::zero(), Vct::zero());
                     }
-                } else {
-                    self.pt(&refl, depth, rng) * re + self.pt(&refr, depth, rng) * tr
+                    if depth + 1 > 5 {
+                        let p = w.x.max(w.y.max(w.z)).min(1.0);
+                        if rng.gen() >= p {
+                            return (Vct::zero(), Vct::zero());
+                        }
+                        w /= p;
+                    }
+                    let direct = self.in_scatter(s.origin, r, m, lambda, rng);
+                    let rest = self.pt(&s, depth + 1, true, medium, lambda, rng);
+                    return (Vct::zero(), w * (direct + rest));
+                },
+            }
+        }
+        match hit {
+            Some((id, hit)) => {
+                let hit = self.layer(hit, r, rng);
+                let (emission, color, scatter) = self.shade(r, id, &hit, depth + 1, lambda, rng);
+                let emission =
+                    if sampled && self.lighting().sampled[id] { Vct::zero() } else { emission };
+                if let Scatter::None = scatter {
+                    return (weight * emission, Vct::zero());
                 }
-            };
-            return texture.emission + color * ff();
+                let direct = self.direct(&hit, r, medium, lambda, rng);
+                let absorption = spectral(hit.texture.absorption, lambda);
+                let lambda = Self::hero(&hit.texture, lambda);
+                let sampled = Self::sampled(&hit, sampled);
+                let rest = direct
+                    + scatter.sum(|s, w| {
+                        let w = self.absorb(id, hit.norm, absorption, s) * w;
+                        let medium = self.next_medium(id, &hit, r, s, medium);
+                        self.pt(s, depth + 1, sampled, medium, lambda, rng) * w
+                    });
+                (weight * emission, weight * color * rest)
+            },
+            None => match self.sky {
+                Some(ref sky) => (weight * spectral(sky.radiance(r.direct), lambda), Vct::zero()),
+                None => (Vct::zero(), Vct::zero()),
+            },
         }
-        Vct::zero()
+    }
+
+    // the direct light counted the emitters for the rays leaving hit, also when they only go
+    // on through it
+    fn sampled(hit: &HitResult, sampled: bool) -> bool {
+        match hit.texture.material {
+            Material::Diffuse => true,
+            Material::Null => sampled,
+            _ => false,
+        }
+    }
+
+    fn pt(
+        &self,
+        r: &Ray,
+        depth: usize,
+        sampled: bool,
+        medium: Option<usize>,
+        lambda: Option<Vct>,
+        rng: &mut dyn Sampler,
+    ) -> Vct {
+        let (emission, rest) = self.pt_split(r, depth, sampled, medium, lambda, rng);
+        emission + rest
+    }
+
+    // pt from the camera which also records the first hit for the AOV passes, in RGB
+    fn pt_aov(&self, r: &Ray, lambda: Option<Vct>, rng: &mut dyn Sampler) -> (Vct, AovSample) {
+        let (id, hit) = match self.find_obj(r) {
+            Some(h) => h,
+            None => return (self.pt(r, 0, false, self.medium, lambda, rng), AovSample::default()),
+        };
+        let rgb = |c: Vct| lambda.map_or(c, |l| to_rgb(c, l));
+        let normal = if hit.norm.dot(r.direct) < 0.0 { hit.norm } else { -hit.norm };
+        let mut sample = AovSample {
+            depth: (hit.pos - r.origin).dot(self.camera.direct.norm()),
+            position: hit.pos,
+            normal,
+            albedo: hit.texture.color,
+            object: id + 1,
+            material: hit.texture.material as usize + 1,
+            ..Default::default()
+        };
+        let hit = self.layer(hit, r, rng);
+        if self.medium.is_some() {
+            // the light scattered before the first hit can't be split, it is all indirect
+            let c = self.pt(r, 0, false, self.medium, lambda, rng);
+            sample.indirect = rgb(c);
+            return (c, sample);
+        }
+        let (emission, color, scatter) = self.shade(r, id, &hit, 1, lambda, rng);
+        let absorption = spectral(hit.texture.absorption, lambda);
+        let hero = Self::hero(&hit.texture, lambda);
+        let mut indirect = Vct::zero();
+        let lights = match scatter {
+            Scatter::None => Vct::zero(),
+            _ => self.direct(&hit, r, None, lambda, rng),
+        };
+        let direct = lights + scatter.sum(|s, w| {
+            let medium = self.next_medium(id, &hit, r, s, None);
+            let sampled = Self::sampled(&hit, false);
+            let (e, rest) = self.pt_split(s, 1, sampled, medium, hero, rng);
+            let w = self.absorb(id, hit.norm, absorption, s) * w;
+            indirect += rest * w;
+            e * w
+        });
+        let (direct, indirect) = (color * direct, color * indirect);
+        sample.direct = rgb(direct);
+        sample.indirect = rgb(indirect);
+        sample.emission = rgb(emission);
+        (emission + direct + indirect, sample)
+    }
+
+    // pixels of the crop window that still need samples: below cfg.sample (unbounded with
+    // a time limit), or still too noisy in adaptive mode
+    fn active_pixels(&self, cfg: &PT, stats: &[Stat], w: usize, h: usize) -> Vec<bool> {
+        let (x0, y0, x1, y1) = cfg.crop.map_or((0, 0, w, h), |c| c.window(w, h));
+        let mut active = vec![false; w * h];
+        match &cfg.adaptive {
+            Some(a) => {
+                // use the largest error around a pixel so that it doesn't stop too early by luck
+                let error: Vec<Flt> = stats.iter().map(|s| s.error()).collect();
+                let max_sample = a.max_sample.max(cfg.sample);
+                (x0..x1).for_each(|x| (y0..y1).for_each(|y| {
+                    let n = stats[y * w + x].n;
+                    let mut e: Flt = 0.0;
+                    for yy in y.saturating_sub(1)..(y + 2).min(h) {
+                        for xx in x.saturating_sub(1)..(x + 2).min(w) {
+                            e = e.max(error[yy * w + xx]);
+                        }
+                    }
+                    active[y * w + x] = n < cfg.sample || (n < max_sample && e >= a.threshold);
+                }));
+            },
+            None => (x0..x1).for_each(|x| (y0..y1).for_each(|y| {
+                active[y * w + x] = cfg.time_limit.is_some() || stats[y * w + x].n < cfg.sample;
+            })),
+        }
+        active
     }
 
     #[cfg_attr(rustfmt, rustfmt_skip)]
-    pub fn path_tracing(&self, p: &mut Image, cfg: PT) {
+    pub fn path_tracing(&self, p: &mut Image, cfg: &PT) {
+        let lighting = self.lighting();
+        assert!(!cfg.emitters || lighting.sampled.contains(&true), "No emissive object can be sampled");
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.thread_num)
             .stack_size(self.stack_size)
@@ -188,62 +784,169 @@ impl World {
             .unwrap();
         pool.install(|| {
         let (w, h) = (p.w, p.h);
-        let (fw, fh) = (w as Flt, h as Flt);
-        let cx = Vct::new(fw * self.camera.view_angle_scale / fh, 0.0, 0.0);
-        let cy = (cx % self.camera.direct).norm() * self.camera.view_angle_scale;
-        let sample = cfg.sample / 4;
-        let inv = 1.0 / sample as Flt;
-        let camera_direct = self.camera.direct.norm();
-        let max_dim = camera_direct.x.abs().max(camera_direct.y.abs().max(camera_direct.z.abs()));
-        let choose = if max_dim == camera_direct.x.abs() { 0 } else if max_dim == camera_direct.y.abs() { 1 } else { 2 };
-        let mut pb = ProgressBar::new((w * h) as u64);
-        pb.set_max_refresh_rate(Some(Duration::from_secs(1)));
-        let mut data: Vec<(usize, usize)> = Vec::new();
-        (0..w).for_each(|x| (0..h).for_each(|y| data.push((x, y))));
-        data.shuffle(&mut rand::thread_rng());
-        let pb = Mutex::new(pb);
-        let p = Mutex::new(p);
-
-        println!("w: {}, h: {}, sample: {}, actual sample: {}", w, h, cfg.sample, sample * 4);
+        let max_sample = cfg.adaptive.as_ref().map_or(cfg.sample, |a| a.max_sample.max(cfg.sample));
+        let pass_sample = if cfg.pass_sample == 0 { cfg.sample } else { cfg.pass_sample };
+        let (mut film, mut stats, mut pass, aov) = match self.progressive.load() {
+            Some(Checkpoint::PT { w: cw, h: ch, pass, film, stats, aov }) => {
+                assert!(cw == w && ch == h, "The checkpoint is {}x{}", cw, ch);
+                (film, stats, pass, aov)
+            },
+            Some(_) => panic!("The checkpoint is not for PT"),
+            None => (Film::new(0, 0, w, h), vec![Stat::default(); w * h], 0, None),
+        };
+        // the crop is rendered again, whatever the checkpoint has there
+        if let Some(c) = cfg.crop {
+            let (x0, y0, x1, y1) = c.window(w, h);
+            film.clear(x0, y0, x1, y1);
+            (x0..x1).for_each(|x| (y0..y1).for_each(|y| stats[y * w + x] = Stat::default()));
+        }
+        let film = Mutex::new(film);
+        // the denoiser needs the guides even when they are not saved
+        let mut passes = self.aovs.as_ref().map_or(Vec::new(), |a| a.passes.clone());
+        if self.denoise.is_some() {
+            for &pass in [AovPass::Albedo, AovPass::Normal, AovPass::Depth].iter() {
+                if !passes.contains(&pass) {
+                    passes.push(pass);
+                }
+            }
+        }
+        // the passes restart when they are not the ones in the checkpoint
+        let passes = if passes.is_empty() { None } else { Some(&passes[..]) };
+        let mut aov = match (aov, passes) {
+            (Some(aov), Some(passes)) if aov.passes == passes => Some(aov),
+            (_, passes) => passes.map(|passes| AovFilm::new(passes, w, h)),
+        };
+        if let (Some(aov), Some(c)) = (aov.as_mut(), cfg.crop) {
+            let (x0, y0, x1, y1) = c.window(w, h);
+            (x0..x1).for_each(|x| (y0..y1).for_each(|y| aov.clear(y * w + x)));
+        }
+
+        println!("w: {}, h: {}, sample: {}, pass sample: {}, filter: {:?}", w, h, cfg.sample, pass_sample, self.filter);
+        if let Some(a) = &cfg.adaptive {
+            println!("adaptive threshold: {}, pass sample: {}, max sample: {}", a.threshold, a.pass_sample, max_sample);
+        }
+        if let Some(t) = cfg.time_limit {
+            println!("time limit: {}s", t);
+        }
         println!("Start rendering with {} threads.", pool.current_num_threads());
         let s_time = time::Instant::now();
+        let time_out = || cfg.time_limit.is_some_and(|t| s_time.elapsed().as_secs_f64() >= t);
 
-        data.into_par_iter().for_each(|(x, y)| {
-            let mut sum = Vct::zero();
-            let (fx, fy) = (x as Flt, y as Flt);
-            let mut rng = Rng::new((y * w + x) as u32);
-            for sx in 0..2 {
-                for sy in 0..2 {
-                    let mut c = Vct::zero();
-                    for _ in 0..sample {
-                        let (fsx, fsy) = (sx as Flt, sy as Flt);
-                        let ccx = cx * (((fsx + 0.5 + Self::gen(&mut rng)) / 2.0 + fx) / fw - 0.5);
-                        let ccy = cy * (((fsy + 0.5 + Self::gen(&mut rng)) / 2.0 + fy) / fh - 0.5);
-                        let rand_b = rng.gen() - 0.5;
-                        let rand_a = rng.gen() - 0.5;
-                        let d = camera_direct;
-                        let r = if choose == 0 {
-                            let (y, z) = (rand_a * d.y, rand_b * d.z);
-                            Vct::new(-(y + z) / d.x, rand_a, rand_b)
-                        } else if choose == 1 {
-                            let (x, z) = (rand_a * d.x, rand_b * d.z);
-                            Vct::new(rand_a, -(x + z) / d.y, rand_b)
-                        } else {
-                            let (x, y) = (rand_a * d.x, rand_b * d.y);
-                            Vct::new(rand_a, rand_b, -(x + y) / d.z)
-                        }.norm() * self.camera.aperture * rng.gen();
-                        let d = ccx + ccy + d;
-                        let o = self.camera.origin + r + d * self.camera.plane_distance;
-                        let d = (d.norm() * self.camera.focal_distance - r).norm();
-                        c += self.pt(&Ray::new(o, d), 0, &mut rng) * inv;
+        loop {
+            let active = self.active_pixels(cfg, &stats, w, h);
+            let (x0, y0, x1, y1) = cfg.crop.map_or((0, 0, w, h), |c| c.window(w, h));
+            let tiles: Vec<_> = cfg.tile.split(x0, y0, x1, y1).into_iter().filter(|&(tx0, ty0, tx1, ty1)| {
+                (ty0..ty1).any(|y| (tx0..tx1).any(|x| active[y * w + x]))
+            }).collect();
+            let cnt = active.iter().filter(|&&a| a).count();
+            if tiles.is_empty() || time_out() {
+                break;
+            }
+            pass += 1;
+            println!("Pass: {}, pixels: {}, tiles: {}", pass, cnt, tiles.len());
+            let mut pb = ProgressBar::new(cnt as u64);
+            pb.set_max_refresh_rate(Some(Duration::from_secs(1)));
+            let pb = Mutex::new(pb);
+            let updated = Mutex::new(Vec::with_capacity(cnt));
+            // every worker takes the next tile, so that tiles are started in order
+            let next = AtomicUsize::new(0);
+            (0..pool.current_num_threads()).into_par_iter().for_each(|_| loop {
+                let t = next.fetch_add(1, Ordering::SeqCst);
+                if t >= tiles.len() || time_out() {
+                    break;
+                }
+                let (tx0, ty0, tx1, ty1) = tiles[t];
+                let mut rng = self.sampler.build(max_sample);
+                let mut tile = Film::around(tx0, ty0, tx1, ty1, &self.filter, (x0, y0, x1, y1));
+                let mut tile_stats = Vec::new();
+                for y in ty0..ty1 {
+                    for x in tx0..tx1 {
+                        let index = y * w + x;
+                        if !active[index] {
+                            continue;
+                        }
+                        let mut stat = stats[index];
+                        let mut px = AovPixel::default();
+                        let (fx, fy) = (x as Flt, y as Flt);
+                        let end = match &cfg.adaptive {
+                            Some(a) if stat.n >= cfg.sample => (stat.n + a.pass_sample.max(1)).min(max_sample),
+                            _ if cfg.time_limit.is_some() => stat.n + pass_sample,
+                            _ => (stat.n + pass_sample).min(cfg.sample),
+                        };
+                        for i in stat.n..end {
+                            rng.start(index, i);
+                            let (ccx, ccy) = (fx + rng.gen(), fy + rng.gen());
+                            let (u, v) = (rng.gen(), rng.gen());
+                            let lambda = if cfg.spectral { Some(sample_wavelengths(rng.gen())) } else { None };
+                            let c = match (self.camera.ray(w, h, ccx, ccy, u, v), passes) {
+                                (Some(r), Some(passes)) => {
+                                    let (c, s) = self.pt_aov(&r, lambda, &mut *rng);
+                                    px.add(passes, &s);
+                                    c
+                                },
+                                (Some(r), None) => self.pt(&r, 0, false, self.medium, lambda, &mut *rng),
+                                (None, passes) => {
+                                    if let Some(passes) = passes {
+                                        px.add(passes, &AovSample::default());
+                                    }
+                                    Vct::zero()
+                                },
+                            };
+                            let c = lambda.map_or(c, |l| to_rgb(c, l));
+                            tile.add(ccx, ccy, c, &self.filter);
+                            stat.add(luminance(c));
+                        }
+                        tile_stats.push((index, stat, px));
                     }
-                    sum += Vct::new(clamp(c.x), clamp(c.y), clamp(c.z)) * 0.25;
                 }
+                film.lock().unwrap().merge(&tile);
+                pb.lock().unwrap().add(tile_stats.len() as u64);
+                updated.lock().unwrap().extend(tile_stats);
+            });
+            pb.lock().unwrap().finish_println("...done\n");
+            updated.into_inner().unwrap().into_iter().for_each(|(index, stat, px)| {
+                stats[index] = stat;
+                if let Some(aov) = aov.as_mut() {
+                    aov.merge(index, &px);
+                }
+            });
+            let acc = film.lock().unwrap();
+            if self.progressive.snapshot.is_some() {
+                acc.write(p);
+                self.progressive.snapshot(pass, p);
             }
-            p.lock().unwrap().set(x, h - y - 1, sum);
-            pb.lock().unwrap().inc();
-        });
-        pb.lock().unwrap().finish_println("...done\n");
+            if self.progressive.need_checkpoint(pass) {
+                self.progressive.save(&Checkpoint::PT { w, h, pass, film: acc.clone(), stats: stats.clone(), aov: aov.clone() });
+            }
+        }
+        film.lock().unwrap().write(p);
+        if let (Some(aov), Some(cfg)) = (&aov, &self.aovs) {
+            aov.save(cfg);
+        }
+        if let Some(aov) = &aov {
+            let mut variance = vec![0.0; w * h];
+            (0..w).for_each(|x| (0..h).for_each(|y| variance[(h - y - 1) * w + x] = stats[y * w + x].variance()));
+            let guide = Guide {
+                albedo: aov.values(AovPass::Albedo),
+                normal: aov.values(AovPass::Normal),
+                depth: aov.values(AovPass::Depth).map(|d| d.iter().map(|d| d.x).collect()),
+                variance: Some(variance),
+            };
+            self.denoise(p, &guide);
+        }
+
+        let total: usize = stats.iter().map(|s| s.n).sum();
+        println!("Average samples per pixel: {:.2}", total as Flt / (w * h) as Flt);
+        if let Some(path) = cfg.adaptive.as_ref().and_then(|a| a.heatmap.as_ref()) {
+            let mut heatmap = Image::new(w, h);
+            for y in 0..h {
+                for x in 0..w {
+                    let t = stats[y * w + x].n as Flt / max_sample as Flt;
+                    heatmap.set(x, h - y - 1, Vct::new(t, 1.0 - (t * 2.0 - 1.0).abs(), 1.0 - t));
+                }
+            }
+            heatmap.save_png(path);
+        }
         let mils = (time::Instant::now() - s_time).as_millis();
         let days = mils / 1000 / 60 / 60 / 24;
         let hours = mils / 1000 / 60 / 60 - days * 24;
@@ -257,7 +960,7 @@ impl World {
         &self,
         r: &Ray,
         mut depth: usize,
-        rng: &mut Rng,
+        rng: &mut dyn Sampler,
         points: &mut Vec<Point>,
         prod: Vct,
         index: usize,
@@ -273,7 +976,20 @@ impl World {
         if depth > self.max_depth {
             return;
         }
-        if let Some(HitResult { pos, norm, ref texture }) = self.find(r) {
+        if let Some((id, hit)) = self.find_obj(r) {
+            let hit = self.layer(hit, r, rng);
+            let HitResult { pos, norm, ref texture } = hit;
+            if texture.material == Material::Null {
+                self.sppm_1(&Ray::new(pos, r.direct), depth, rng, points, prod, index, prob);
+                return;
+            }
+            // the light leaving at the entry is gathered where the walk comes out
+            if texture.material == Material::Subsurface {
+                if let Some((s, n, w)) = self.subsurface(id, &hit, r, None, rng) {
+                    points.push(Point::new(s.origin, n, prod * w, index));
+                }
+                return;
+            }
             let mut color = texture.color;
             if depth > 5 {
                 let p = color.x.max(color.y.max(color.z));
@@ -294,34 +1010,110 @@ impl World {
                 self.sppm_1(&refl, depth, rng, points, prod, index, prob);
                 return;
             }
+            // reflections inside the object are attenuated as well
+            let absorb = |s: &Ray| self.absorb(id, norm, texture.absorption, s);
             let w = if nd < 0.0 { norm } else { -norm };
             let (it, ddw) = (norm.dot(w) > 0.0, r.direct.dot(w));
-            let (n, sign) = if it { (self.n1, 1.0) } else { (self.n2, -1.0) };
+            let (n1, n2, r0) = self.ior(texture, None);
+            let (n, sign) = if it { (n1, 1.0) } else { (n2, -1.0) };
             let cos2t = 1.0 - n * n * (1.0 - ddw * ddw);
             if cos2t < 0.0 {
-                self.sppm_1(&refl, depth, rng, points, prod, index, prob);
+                self.sppm_1(&refl, depth, rng, points, prod * absorb(&refl), index, prob);
                 return;
             }
             let td = (r.direct * n - norm * ((ddw * n + cos2t.sqrt()) * sign)).norm();
             let refr = Ray::new(pos, td);
             let c = if it { 1.0 + ddw } else { 1.0 - td.dot(norm) };
             let cc = c * c;
-            let re = self.r0 + (1.0 - self.r0) * cc * cc * c;
+            let re = r0 + (1.0 - r0) * cc * cc * c;
             let tr = 1.0 - re;
             if depth > 2 {
                 let p = 0.25 + 0.5 * re;
                 if rng.gen() < p {
+                    let prod = prod * absorb(&refl);
                     self.sppm_1(&refl, depth, rng, points, prod, index, prob * (re / p));
                 } else {
+                    let prod = prod * absorb(&refr);
                     self.sppm_1(&refr, depth, rng, points, prod, index, prob * (tr / (1.0 - p)));
                 }
             } else {
-                self.sppm_1(&refl, depth, rng, points, prod, index, prob * re);
-                self.sppm_1(&refr, depth, rng, points, prod, index, prob * tr);
+                self.sppm_1(&refl, depth, rng, points, prod * absorb(&refl), index, prob * re);
+                self.sppm_1(&refr, depth, rng, points, prod * absorb(&refr), index, prob * tr);
             }
         }
     }
 
+    // the emissive objects which can be sampled, with the luminance of their power, and the
+    // sphere around the points sampled on all the objects. Emissive surfaces light both of
+    // their sides, as paths see them from either.
+    fn emitters(&self) -> (Vec<(Source, Flt)>, (Vct, Flt)) {
+        let mut rng = Rng::new(0);
+        let mut ret = Vec::new();
+        let (mut min, mut max) = (Vct::one() * Flt::INFINITY, Vct::one() * -Flt::INFINITY);
+        for (i, obj) in self.objs.iter().enumerate() {
+            let power = (0..LIGHT_PROBE)
+                .filter_map(|_| {
+                    let (hit, area) = obj.sample(&mut rng)?;
+                    min = min.min(hit.pos);
+                    max = max.max(hit.pos);
+                    let k = self.profile(i, Self::cosine(Self::side(hit.norm, &mut rng), &mut rng));
+                    Some(luminance(hit.texture.emission) * area * 2.0 * PI * k)
+                })
+                .sum::<Flt>()
+                / LIGHT_PROBE as Flt;
+            if power > 0.0 {
+                ret.push((Source::Object(i), power));
+            }
+        }
+        // only infinite objects, any size does
+        let bound = if min.x <= max.x {
+            ((min + max) * 0.5, ((max - min) * 0.5).len().max(EPS))
+        } else {
+            (Vct::zero(), 1.0)
+        };
+        (ret, bound)
+    }
+
+    // The sphere around most of the view points, those far away on open ground or in the sky
+    // seen in mirrors would spread the photons of directional lights too thin.
+    fn bound(points: &[Point]) -> (Vct, Flt) {
+        if points.is_empty() {
+            return (Vct::zero(), EPS);
+        }
+        let median = |mut v: Vec<Flt>| {
+            let mid = v.len() / 2;
+            *v.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap()).1
+        };
+        let center = Vct::new(
+            median(points.iter().map(|p| p.pos.x).collect()),
+            median(points.iter().map(|p| p.pos.y).collect()),
+            median(points.iter().map(|p| p.pos.z).collect()),
+        );
+        let mut dist: Vec<Flt> = points.iter().map(|p| (p.pos - center).len()).collect();
+        let k = (dist.len() - 1) * 90 / 100;
+        let radius = *dist.select_nth_unstable_by(k, |a, b| a.partial_cmp(b).unwrap()).1;
+        (center, radius.max(EPS))
+    }
+
+    // A photon leaving a source picked by power, and its flux over the probability of the pick,
+    // so that the fluxes average to the total power of the sources.
+    fn emit(&self, lighting: &Lighting, rng: &mut Rng) -> (Ray, Vct) {
+        let (source, p) = lighting.pick(rng.gen());
+        let (center, radius) = lighting.bound;
+        match source {
+            Source::Object(i) => {
+                let (hit, area) = self.objs[i].sample(rng).unwrap();
+                let d = Self::cosine(Self::side(hit.norm, rng), rng);
+                let k = area * 2.0 * PI * self.profile(i, d) / p;
+                (Ray::new(hit.pos, d), hit.texture.emission * k)
+            },
+            Source::Light(i) => {
+                let (r, flux) = self.lights[i].emit(center, radius, rng);
+                (r, flux / p)
+            },
+        }
+    }
+
     fn sppm_2(
         &self,
         r: &Ray,
@@ -338,7 +1130,20 @@ impl World {
         if depth > self.max_depth {
             return;
         }
-        if let Some(HitResult { pos, norm, ref texture }) = self.find(r) {
+        if let Some((id, hit)) = self.find_obj(r) {
+            let hit = self.layer(hit, r, rng);
+            let HitResult { pos, norm, ref texture } = hit;
+            if texture.material == Material::Null {
+                self.sppm_2(&Ray::new(pos, r.direct), depth, rng, tree, pixels, flux);
+                return;
+            }
+            if texture.material == Material::Subsurface {
+                tree.update(&pos, &norm, &flux, pixels);
+                if let Some((s, _, w)) = self.subsurface(id, &hit, r, None, rng) {
+                    self.sppm_2(&s, depth, rng, tree, pixels, flux * w);
+                }
+                return;
+            }
             let mut color = texture.color;
             if depth > 5 {
                 let p = color.x.max(color.y.max(color.z));
@@ -371,29 +1176,32 @@ impl World {
                 self.sppm_2(&refl, depth, rng, tree, pixels, flux);
                 return;
             }
+            // reflections inside the object are attenuated as well
+            let absorb = |s: &Ray| self.absorb(id, norm, texture.absorption, s);
             let w = if nd < 0.0 { norm } else { -norm };
             let (it, ddw) = (norm.dot(w) > 0.0, r.direct.dot(w));
-            let (n, sign) = if it { (self.n1, 1.0) } else { (self.n2, -1.0) };
+            let (n1, n2, r0) = self.ior(texture, None);
+            let (n, sign) = if it { (n1, 1.0) } else { (n2, -1.0) };
             let cos2t = 1.0 - n * n * (1.0 - ddw * ddw);
             if cos2t < 0.0 {
-                self.sppm_2(&refl, depth, rng, tree, pixels, flux);
+                self.sppm_2(&refl, depth, rng, tree, pixels, flux * absorb(&refl));
                 return;
             }
             let td = (r.direct * n - norm * ((ddw * n + cos2t.sqrt()) * sign)).norm();
             let refr = Ray::new(pos, td);
             let c = if it { 1.0 + ddw } else { 1.0 - td.dot(norm) };
             let cc = c * c;
-            let re = self.r0 + (1.0 - self.r0) * cc * cc * c;
+            let re = r0 + (1.0 - r0) * cc * cc * c;
             if depth > 2 {
                 let p = 0.25 + 0.5 * re;
                 if rng.gen() < p {
-                    self.sppm_2(&refl, depth, rng, tree, pixels, flux);
+                    self.sppm_2(&refl, depth, rng, tree, pixels, flux * absorb(&refl));
                 } else {
-                    self.sppm_2(&refr, depth, rng, tree, pixels, flux);
+                    self.sppm_2(&refr, depth, rng, tree, pixels, flux * absorb(&refr));
                 }
             } else {
-                self.sppm_2(&refl, depth, rng, tree, pixels, flux);
-                self.sppm_2(&refr, depth, rng, tree, pixels, flux);
+                self.sppm_2(&refl, depth, rng, tree, pixels, flux * absorb(&refl));
+                self.sppm_2(&refr, depth, rng, tree, pixels, flux * absorb(&refr));
             }
         }
     }
@@ -407,27 +1215,41 @@ impl World {
             .unwrap();
         pool.install(|| {
         let (w, h) = (p.w, p.h);
-        let (fw, fh) = (w as Flt, h as Flt);
-        let cx = Vct::new(fw * self.camera.view_angle_scale / fh, 0.0, 0.0);
-        let cy = (cx % self.camera.direct).norm() * self.camera.view_angle_scale;
         let thread_num = pool.current_num_threads();
         let sample = cfg.view_point_sample / 4;
-        let camera_direct = self.camera.direct.norm();
-        let max_dim = camera_direct.x.abs().max(camera_direct.y.abs().max(camera_direct.z.abs()));
-        let choose = if max_dim == camera_direct.x.abs() { 0 } else if max_dim == camera_direct.y.abs() { 1 } else { 2 };
 
-        let mut radius = cfg.radius;
         let radius_decay = cfg.radius_decay;
         let rounds = cfg.rounds;
         let photon_sample = cfg.photon_sample / thread_num;
+        let (start, mut radius, mut final_pixel) = match self.progressive.load() {
+            Some(Checkpoint::SPPM { w: cw, h: ch, round, radius, pixel }) => {
+                assert!(cw == w && ch == h, "The checkpoint is {}x{}", cw, ch);
+                (round, radius, pixel)
+            },
+            Some(_) => panic!("The checkpoint is not for SPPM"),
+            None => (0, cfg.radius, vec![Vct::zero(); w * h]),
+        };
 
         println!("w: {}, h: {}, view point sample: {}, actual sample: {}", w, h, cfg.view_point_sample, sample * 4);
         println!("photon samples: {}, total rounds: {}, init radius: {}, radius decay: {}", photon_sample * thread_num, rounds, radius, radius_decay);
+        if let Some(t) = cfg.time_limit {
+            println!("time limit: {}s, rounds are not limited", t);
+        }
         println!("Start rendering with {} threads.", pool.current_num_threads());
         let s_time = time::Instant::now();
-        let mut final_pixel = vec![Vct::zero(); w * h];
 
-        for iter in 0..rounds {
+        // the photons don't come from the sky and the view points don't see it
+        assert!(self.sky.is_none(), "The sky is only rendered by PT, remove it for SPPM");
+        let emitters = if cfg.emitters { self.emitters().0 } else { Vec::new() };
+        assert!(!cfg.emitters || !emitters.is_empty(), "No emissive object can emit photons");
+
+        let mut iter = start;
+        loop {
+            match cfg.time_limit {
+                Some(t) if s_time.elapsed().as_secs_f64() >= t => break,
+                None if iter >= rounds => break,
+                _ => (),
+            }
             println!("Round: {}, radius: {}", iter + 1, radius);
             let mut data: Vec<(usize, usize)> = Vec::new();
             (0..w).for_each(|x| (0..h).for_each(|y| data.push((x, y))));
@@ -437,32 +1259,22 @@ impl World {
             data.into_par_iter().for_each(|(x, y)| {
                 let (fx, fy) = (x as Flt, y as Flt);
                 let index = y * w + x;
-                let mut rng = Rng::new((index + iter * w * h) as u32);
+                let n = sample * 4;
+                let mut rng = self.sampler.build(n);
                 let mut points = vec![];
-                for sx in 0..2 {
-                    for sy in 0..2 {
-                        for _ in 0..sample {
-                            let (fsx, fsy) = (sx as Flt, sy as Flt);
-                            let ccx = cx * (((fsx + 0.5 + Self::gen(&mut rng)) / 2.0 + fx) / fw - 0.5);
-                            let ccy = cy * (((fsy + 0.5 + Self::gen(&mut rng)) / 2.0 + fy) / fh - 0.5);
-                            let rand_b = rng.gen() - 0.5;
-                            let rand_a = rng.gen() - 0.5;
-                            let d = camera_direct;
-                            let r = if choose == 0 {
-                                let (y, z) = (rand_a * d.y, rand_b * d.z);
-                                Vct::new(-(y + z) / d.x, rand_a, rand_b)
-                            } else if choose == 1 {
-                                let (x, z) = (rand_a * d.x, rand_b * d.z);
-                                Vct::new(rand_a, -(x + z) / d.y, rand_b)
-                            } else {
-                                let (x, y) = (rand_a * d.x, rand_b * d.y);
-                                Vct::new(rand_a, rand_b, -(x + y) / d.z)
-                            }.norm() * self.camera.aperture * rng.gen();
-                            let d = ccx + ccy + d;
-                            let o = self.camera.origin + r + d * self.camera.plane_distance;
-                            let d = (d.norm() * self.camera.focal_distance - r).norm();
-                            self.sppm_1(&Ray::new(o, d), 0, &mut rng, &mut points, Vct::one() * 0.35 / (iter + 1) as Flt, index, 1.0);
-                        }
+                // like the samples of PT the view points spread over the pixel, the filter weighs
+                // them over their total weight
+                let total = (0..n).map(|i| {
+                    rng.start(index, iter * n + i);
+                    self.filter.eval(rng.gen() - 0.5, rng.gen() - 0.5)
+                }).sum::<Flt>().max(EPS);
+                for i in 0..n {
+                    rng.start(index, iter * n + i);
+                    let (dx, dy) = (rng.gen(), rng.gen());
+                    let wt = self.filter.eval(dx - 0.5, dy - 0.5) * n as Flt / total;
+                    let (u, v) = (rng.gen(), rng.gen());
+                    if let Some(r) = self.camera.ray(w, h, fx + dx, fy + dy, u, v) {
+                        self.sppm_1(&r, 0, &mut *rng, &mut points, Vct::one() * (0.35 * wt / (iter + 1) as Flt), index, 1.0);
                     }
                 }
                 total_points.lock().unwrap().append(&mut points);
@@ -471,6 +1283,12 @@ impl World {
             let mut total_points = total_points.lock().unwrap();
             println!("Total view points: {}", total_points.len());
 
+            // the sphere around the view points, which photons of directional lights cover
+            let bound = Self::bound(&total_points);
+            let mut sources = emitters.clone();
+            sources.extend(self.lights.iter().enumerate().map(|(i, l)| (Source::Light(i), luminance(l.power(bound.1)))));
+            let lighting = Lighting::new(sources, self.objs.len(), bound);
+
             println!("Building tree");
             let tree = KDTree::new(&mut total_points, radius);
             println!("...done");
@@ -484,16 +1302,21 @@ impl World {
                 let mut pixels = vec![Pixel::default(); w * h];
                 let mut rng = Rng::new((iter * thread_num + index) as u32);
                 for i in 1..=photon_sample {
-                    let ang = rng.gen() * PI * 2.0;
-                    let r = rng.gen() * cfg.light_r;
-                    let o = Vct::new(cfg.light_pos.x + r * ang.cos(), cfg.light_pos.y, cfg.light_pos.z + r * ang.sin());
-                    let t1 = rng.gen() * PI * 2.0;
-                    let t2 = rng.gen() * PI * 2.0;
-                    let mut d = Vct::new(t1.sin() * t2.cos(), t1.sin() * t2.sin(), t1.cos()).norm();
-                    if d.y < 0.0 {
-                        d.y = -d.y;
-                    }
-                    self.sppm_2(&Ray::new(o, d), 0, &mut rng, &tree, &mut pixels, Vct::one());
+                    let (r, flux) = if !lighting.sources.is_empty() {
+                        self.emit(&lighting, &mut rng)
+                    } else {
+                        let ang = rng.gen() * PI * 2.0;
+                        let r = rng.gen() * cfg.light_r;
+                        let o = Vct::new(cfg.light_pos.x + r * ang.cos(), cfg.light_pos.y, cfg.light_pos.z + r * ang.sin());
+                        let t1 = rng.gen() * PI * 2.0;
+                        let t2 = rng.gen() * PI * 2.0;
+                        let mut d = Vct::new(t1.sin() * t2.cos(), t1.sin() * t2.sin(), t1.cos()).norm();
+                        if d.y < 0.0 {
+                            d.y = -d.y;
+                        }
+                        (Ray::new(o, d), Vct::one())
+                    };
+                    self.sppm_2(&r, 0, &mut rng, &tree, &mut pixels, flux);
                     if i % 100 == 0 {
                         pb.lock().unwrap().add(100);
                     }
@@ -512,19 +1335,28 @@ impl World {
             }
             radius *= radius_decay;
 
-            for x in 0..w {
-                for y in 0..h {
-                    p.set(x, h - y - 1, final_pixel[y * w + x]);
+            if self.progressive.snapshot.is_some() {
+                for x in 0..w {
+                    for y in 0..h {
+                        p.set(x, h - y - 1, final_pixel[y * w + x]);
+                    }
                 }
+                self.progressive.snapshot(iter + 1, p);
+            }
+            if self.progressive.need_checkpoint(iter + 1) {
+                let pixel = final_pixel.clone();
+                self.progressive.save(&Checkpoint::SPPM { w, h, round: iter + 1, radius, pixel });
             }
-            p.save_png(&format!("./result/test/test_{}.png", iter));
+            iter += 1;
         }
+        println!("Rounds: {}", iter);
 
         for x in 0..w {
             for y in 0..h {
                 p.set(x, h - y - 1, final_pixel[y * w + x]);
             }
         }
+        self.denoise(p, &Guide::default());
 
         let mils = (time::Instant::now() - s_time).as_millis();
         let days = mils / 1000 / 60 / 60 / 24;
@@ -0,0 +1,70 @@
+use crate::Flt;
+
+// Walker's alias table, picking indices in proportion to their weights in constant time
+#[derive(Clone, Debug, Default)]
+pub struct Alias {
+    prob: Vec<Flt>, // of keeping the column instead of going to its alias
+    alias: Vec<usize>,
+    pdf: Vec<Flt>,
+}
+
+impl Alias {
+    pub fn new(weights: &[Flt]) -> Self {
+        let n = weights.len();
+        let total = weights.iter().sum::<Flt>();
+        if n == 0 || total <= 0.0 {
+            return Self::default();
+        }
+        let pdf: Vec<Flt> = weights.iter().map(|w| w / total).collect();
+        let mut prob: Vec<Flt> = pdf.iter().map(|p| p * n as Flt).collect();
+        let mut alias = vec![0; n];
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|&i| prob[i] < 1.0);
+        while let (Some(&s), Some(&l)) = (small.last(), large.last()) {
+            small.pop();
+            alias[s] = l;
+            prob[l] -= 1.0 - prob[s];
+            if prob[l] < 1.0 {
+                large.pop();
+                small.push(l);
+            }
+        }
+        // what is left only misses 1 by rounding
+        small.into_iter().chain(large).for_each(|i| prob[i] = 1.0);
+        Self { prob, alias, pdf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pdf.is_empty()
+    }
+
+    // an index for u in [0, 1) and the probability to pick it
+    pub fn sample(&self, u: Flt) -> (usize, Flt) {
+        let n = self.prob.len();
+        let x = u * n as Flt;
+        let i = (x as usize).min(n - 1);
+        let i = if x - (i as Flt) < self.prob[i] { i } else { self.alias[i] };
+        (i, self.pdf[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alias() {
+        let weights = [1.0, 0.0, 3.0, 0.5, 0.5];
+        let table = Alias::new(&weights);
+        let n = 100000;
+        let mut count = [0; 5];
+        for k in 0..n {
+            let (i, p) = table.sample((k as Flt + 0.5) / n as Flt);
+            assert!((p - weights[i] / 5.0).abs() < 1e-9);
+            count[i] += 1;
+        }
+        for (c, w) in count.iter().zip(weights.iter()) {
+            assert!((*c as Flt / n as Flt - w / 5.0).abs() < 1e-3);
+        }
+        assert!(Alias::new(&[0.0, 0.0]).is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+use super::{luminance, Image};
+use crate::{linalg::Vct, Deserialize, Flt, Serialize};
+use rayon::prelude::*;
+
+fn default_iterations() -> usize {
+    5
+}
+
+fn default_sigma_color() -> Flt {
+    4.0
+}
+
+fn default_sigma_normal() -> Flt {
+    64.0
+}
+
+fn default_sigma_depth() -> Flt {
+    0.02
+}
+
+fn default_sigma_albedo() -> Flt {
+    0.1
+}
+
+// Edge-avoiding a-trous wavelet filter, see Dammertz et al., "Edge-Avoiding A-Trous Wavelet
+// Transform for fast Global Illumination Filtering" and Schied et al., "Spatiotemporal
+// Variance-Guided Filtering". The larger a sigma is, the more that guide lets pixels blend.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Denoise {
+    #[serde(default = "default_iterations")]
+    pub iterations: usize,
+    #[serde(default = "default_sigma_color")]
+    pub sigma_color: Flt, // in standard deviations of the noise
+    #[serde(default = "default_sigma_normal")]
+    pub sigma_normal: Flt, // exponent of the cosine, so smaller is looser
+    #[serde(default = "default_sigma_depth")]
+    pub sigma_depth: Flt, // relative to the depth
+    #[serde(default = "default_sigma_albedo")]
+    pub sigma_albedo: Flt,
+    #[serde(default)]
+    pub noisy: Option<String>, // also save the image before denoising
+}
+
+impl Default for Denoise {
+    fn default() -> Self {
+        Self {
+            iterations: default_iterations(),
+            sigma_color: default_sigma_color(),
+            sigma_normal: default_sigma_normal(),
+            sigma_depth: default_sigma_depth(),
+            sigma_albedo: default_sigma_albedo(),
+            noisy: None,
+        }
+    }
+}
+
+// Optional buffers of the first hit, with the same size and orientation as the image. Depth
+// is 0 where nothing is hit. The variance is the one of the mean luminance of every pixel, it
+// is estimated from the neighborhood when missing.
+#[derive(Clone, Debug, Default)]
+pub struct Guide {
+    pub albedo: Option<Vec<Vct>>,
+    pub normal: Option<Vec<Vct>>,
+    pub depth: Option<Vec<Flt>>,
+    pub variance: Option<Vec<Flt>>,
+}
+
+const KERNEL: [Flt; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+const WEIGHT_EPS: Flt = 1e-3; // the smallest albedo divided out of the colors
+
+fn blur3(v: &[Flt], w: usize, h: usize) -> Vec<Flt> {
+    let k = [0.25, 0.5, 0.25];
+    let mut out = vec![0.0; w * h];
+    for y in 0..h {
+        for x in 0..w {
+            let (mut s, mut ws) = (0.0, 0.0);
+            for dy in 0..3 {
+                for dx in 0..3 {
+                    let (xx, yy) = ((x + dx) as isize - 1, (y + dy) as isize - 1);
+                    if xx >= 0 && yy >= 0 && (xx as usize) < w && (yy as usize) < h {
+                        let wt = k[dx] * k[dy];
+                        s += v[yy as usize * w + xx as usize] * wt;
+                        ws += wt;
+                    }
+                }
+            }
+            out[y * w + x] = s / ws;
+        }
+    }
+    out
+}
+
+// the median of the variances of the luminance in 3x3 neighborhoods, so that edges are not
+// taken for noise
+fn estimate_variance(lum: &[Flt], w: usize, h: usize) -> Vec<Flt> {
+    let mean = blur3(lum, w, h);
+    let sq: Vec<Flt> = lum.iter().map(|l| l * l).collect();
+    let mean_sq = blur3(&sq, w, h);
+    let mut var: Vec<Flt> = mean.iter().zip(mean_sq).map(|(m, s)| (s - m * m).max(0.0)).collect();
+    let mid = var.len() / 2;
+    pdqselect::select_by(&mut var, mid, |a, b| a.partial_cmp(b).unwrap());
+    vec![var[mid]; w * h]
+}
+
+impl Image {
+    pub fn denoise(&self, guide: &Guide, cfg: &Denoise) -> Image {
+        let (w, h) = (self.w, self.h);
+        // filter the light arriving at the surfaces instead of the textured color
+        let albedo: Vec<Vct> = match &guide.albedo {
+            Some(a) => a.iter().map(|a| a.max(Vct::one() * WEIGHT_EPS)).collect(),
+            None => vec![Vct::one(); w * h],
+        };
+        let mut color: Vec<Vct> =
+            self.c.iter().zip(albedo.iter()).map(|(c, a)| Vct::new(c.0, c.1, c.2) / *a).collect();
+        let mut var: Vec<Flt> = match &guide.variance {
+            Some(v) => {
+                v.iter().zip(albedo.iter()).map(|(v, a)| v / luminance(*a).powi(2)).collect()
+            },
+            None => {
+                let lum: Vec<Flt> = color.iter().map(|&c| luminance(c)).collect();
+                estimate_variance(&lum, w, h)
+            },
+        };
+        for it in 0..cfg.iterations {
+            let step = 1isize << it;
+            let lum: Vec<Flt> = color.iter().map(|&c| luminance(c)).collect();
+            let sigma: Vec<Flt> =
+                blur3(&var, w, h).iter().map(|v| cfg.sigma_color * v.sqrt() + 1e-6).collect();
+            let filtered: Vec<(Vct, Flt)> = (0..w * h)
+                .into_par_iter()
+                .map(|p| {
+                    let (x, y) = (p % w, p / w);
+                    let (mut sum, mut wsum, mut vsum) = (Vct::zero(), 0.0, 0.0);
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            let xx = x as isize + (i as isize - 2) * step;
+                            let yy = y as isize + (j as isize - 2) * step;
+                            if xx < 0 || yy < 0 || xx as usize >= w || yy as usize >= h {
+                                continue;
+                            }
+                            let q = yy as usize * w + xx as usize;
+                            let mut wt = kx * ky * (-(lum[p] - lum[q]).abs() / sigma[p]).exp();
+                            match &guide.normal {
+                                // misses have no normal, the depth keeps them apart
+                                Some(n) if n[p].dot(n[p]) > 0.0 => {
+                                    wt *= n[p].dot(n[q]).max(0.0).powf(cfg.sigma_normal);
+                                },
+                                _ => (),
+                            }
+                            if let Some(d) = &guide.depth {
+                                let s = cfg.sigma_depth * step as Flt * d[p] + 1e-6;
+                                wt *= (-(d[p] - d[q]).abs() / s).exp();
+                            }
+                            if let Some(a) = &guide.albedo {
+                                let d = a[p] - a[q];
+                                wt *= (-d.dot(d) / (cfg.sigma_albedo * cfg.sigma_albedo)).exp();
+                            }
+                            sum += color[q] * wt;
+                            wsum += wt;
+                            vsum += wt * wt * var[q];
+                        }
+                    }
+                    if wsum > 0.0 {
+                        (sum / wsum, vsum / (wsum * wsum))
+                    } else {
+                        (color[p], var[p])
+                    }
+                })
+                .collect();
+            let (out, out_var) = filtered.into_iter().unzip();
+            color = out;
+            var = out_var;
+        }
+        let mut p = Image::new(w, h);
+        p.c = color
+            .iter()
+            .zip(albedo.iter())
+            .map(|(&c, &a)| {
+                let c = c * a;
+                (c.x, c.y, c.z, 0.0)
+            })
+            .collect();
+        p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Rng;
+
+    #[test]
+    fn denoise() {
+        let (w, h) = (32, 32);
+        let mut rng = Rng::new(1);
+        let mut p = Image::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let v = if x < w / 2 { 0.2 } else { 0.8 };
+                p.set(x, y, Vct::one() * (v + (rng.gen() - 0.5) * 0.1));
+            }
+        }
+        let q = p.denoise(&Guide::default(), &Denoise::default());
+        let err = |p: &Image| {
+            let mut e = 0.0;
+            for y in 0..h {
+                for x in 0..w {
+                    let v = if x < w / 2 { 0.2 } else { 0.8 };
+                    e += (p.get(x, y).0 - v).powi(2);
+                }
+            }
+            e
+        };
+        assert!(err(&q) < err(&p) * 0.5);
+    }
+}
@@ -52,6 +52,22 @@ impl Image {
         println!("...done");
     }
 
+    // linear floats without clamping, rows are stored bottom to top
+    pub fn save_pfm(&self, path: &str) {
+        println!("Writing to {}", path);
+        let errmsg = &format!("cannot save PFM to {}", path);
+        let mut data = format!("PF\n{} {}\n-1.0\n", self.w, self.h).into_bytes();
+        for y in (0..self.h).rev() {
+            for x in 0..self.w {
+                let t = self.get(x, y);
+                [t.0, t.1, t.2].iter().for_each(|&v| data.extend(&(v as f32).to_le_bytes()));
+            }
+        }
+        let mut file = File::create(path).expect(errmsg);
+        file.write_all(&data).expect(errmsg);
+        println!("...done");
+    }
+
     pub fn save_png(&self, path: &str) {
         println!("Writing to {}", path);
         let mut imgbuf = image::ImageBuffer::new(self.w as u32, self.h as u32);
@@ -1,13 +1,23 @@
+pub mod alias;
+pub mod denoise;
 pub mod image;
+pub mod sampler;
+pub mod spectrum;
 
+pub use self::alias::Alias;
+pub use self::denoise::{Denoise, Guide};
 pub use self::image::Image;
+pub use self::sampler::{Sampler, SamplerType};
 
 use crate::{
     geo::{
-        collection::{BezierRotate, Mesh, Plane, Sphere},
+        collection::{
+            BezierRotate, Cone, Cuboid, Cylinder, Disk, Mesh, Plane, Rectangle, Sphere, Torus,
+        },
         Geo,
     },
-    scene::{Camera, Renderer, World},
+    linalg::{Transform, Vct},
+    scene::{Camera, Renderer, Sky, World},
     Flt,
 };
 use pbr::ProgressBar;
@@ -33,6 +43,10 @@ pub fn to_byte(x: Flt) -> u8 {
     (clamp(x).powf(1.0 / 2.2) * 255.0 + 0.5) as u8
 }
 
+pub fn luminance(c: Vct) -> Flt {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
 pub struct Rng {
     pub seed: u32,
 }
@@ -80,6 +94,33 @@ pub fn from_json(path: &str, custom: HashMap<String, FromJsonFunc>) -> (World, I
     let renderer: Renderer =
         serde_json::from_value(data["renderer"].take()).expect("Invalid renderer");
     let mut w = World::new(camera, max_depth, thread_num, stack_size, na, ng, renderer);
+    if !data["sampler"].is_null() {
+        w.sampler = serde_json::from_value(data["sampler"].take()).expect("Invalid sampler");
+    }
+    if !data["filter"].is_null() {
+        w.filter = serde_json::from_value(data["filter"].take()).expect("Invalid filter");
+    }
+    if !data["progressive"].is_null() {
+        w.progressive =
+            serde_json::from_value(data["progressive"].take()).expect("Invalid progressive");
+    }
+    if !data["denoise"].is_null() {
+        w.denoise = Some(serde_json::from_value(data["denoise"].take()).expect("Invalid denoise"));
+    }
+    if !data["aovs"].is_null() {
+        w.aovs = Some(serde_json::from_value(data["aovs"].take()).expect("Invalid aovs"));
+    }
+    if !data["lights"].is_null() {
+        w.lights = serde_json::from_value(data["lights"].take()).expect("Invalid lights");
+    }
+    if !data["sky"].is_null() {
+        let sky: Sky = serde_json::from_value(data["sky"].take()).expect("Invalid sky");
+        w.lights.extend(sky.sun());
+        w.sky = Some(sky);
+    }
+    if !data["medium"].is_null() {
+        w.set_medium(serde_json::from_value(data["medium"].take()).expect("Invalid medium"));
+    }
     match data["objects"].take() {
         Value::Array(objs) => thread::Builder::new()
             .stack_size(stack_size)
@@ -88,22 +129,43 @@ pub fn from_json(path: &str, custom: HashMap<String, FromJsonFunc>) -> (World, I
                 let mut pb = ProgressBar::new(objs.len() as u64);
                 objs.into_iter().for_each(|_obj| {
                     let mut obj = _obj;
+                    // removed, taking them would leave nulls for meshes to refuse
+                    let mut remove = |key| match obj.as_object_mut() {
+                        Some(map) => map.remove(key).unwrap_or(Value::Null),
+                        None => Value::Null,
+                    };
+                    let medium = remove("medium");
+                    let ies = remove("ies");
+                    let transform = obj["transform"].clone();
                     match obj["type"].take() {
                         Value::String(tp) => match tp.as_ref() {
                             "sphere" => w.add(new_from_json::<Sphere>(obj)),
                             "plane" => w.add(new_from_json::<Plane>(obj)),
                             "mesh" => w.add(new_from_json::<Mesh>(obj)),
                             "bezier_rotate" => w.add(new_from_json::<BezierRotate>(obj)),
-                            _ => {
-                                if let Some(f) = custom.get(&tp) {
-                                    w.add(f(obj));
-                                    return;
-                                }
-                                panic!("Unknown obj");
-                            }
+                            "rectangle" => w.add(new_from_json::<Rectangle>(obj)),
+                            "disk" => w.add(new_from_json::<Disk>(obj)),
+                            "box" => w.add(new_from_json::<Cuboid>(obj)),
+                            "cylinder" => w.add(new_from_json::<Cylinder>(obj)),
+                            "cone" => w.add(new_from_json::<Cone>(obj)),
+                            "torus" => w.add(new_from_json::<Torus>(obj)),
+                            _ => match custom.get(&tp) {
+                                Some(f) => w.add(f(obj)),
+                                None => panic!("Unknown obj"),
+                            },
                         },
                         _ => panic!("Invalid obj"),
                     };
+                    if !medium.is_null() {
+                        w.set_interior(serde_json::from_value(medium).expect("Invalid medium"));
+                    }
+                    if !ies.is_null() {
+                        let transform = match transform {
+                            Value::Null => Transform::new(Vec::new()),
+                            t => serde_json::from_value(t).expect("Invalid transform"),
+                        };
+                        w.set_profile(serde_json::from_value(ies).expect("Invalid ies"), transform);
+                    }
                     pb.inc();
                 });
                 pb.finish_println("...loaded\n");
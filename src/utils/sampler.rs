@@ -0,0 +1,300 @@
+use super::Rng;
+use crate::{Deserialize, Flt, Serialize};
+
+const INV32: Flt = 1.0 / 4294967296.0;
+
+// Every call of `gen` consumes the next dimension of the current sample. The
+// first two dimensions are the position on the film, the next two are the lens,
+// and the rest are consumed by the bounces in the order they are needed.
+pub trait Sampler {
+    // start the `index`-th sample of pixel `pixel`
+    fn start(&mut self, pixel: usize, index: usize);
+    fn gen(&mut self) -> Flt;
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SamplerType {
+    #[default]
+    Independent,
+    Stratified {
+        #[serde(default = "default_jitter")]
+        jitter: bool,
+    },
+    Halton,
+    Sobol,
+}
+
+fn default_jitter() -> bool {
+    true
+}
+
+impl SamplerType {
+    // spp is the number of samples expected per pixel, used by stratified sampling
+    pub fn build(&self, spp: usize) -> Box<dyn Sampler> {
+        match self {
+            SamplerType::Independent => Box::new(Rng::new(0)),
+            SamplerType::Stratified { jitter } => Box::new(Stratified::new(spp, *jitter)),
+            SamplerType::Halton => Box::new(Halton::new()),
+            SamplerType::Sobol => Box::new(Sobol::new()),
+        }
+    }
+}
+
+pub fn hash(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^= x >> 33;
+    x
+}
+
+fn hash2(a: u64, b: u64) -> u32 {
+    hash(hash(a) ^ b) as u32
+}
+
+impl Sampler for Rng {
+    fn start(&mut self, pixel: usize, index: usize) {
+        *self = Rng::new(hash2(pixel as u64, index as u64));
+    }
+
+    fn gen(&mut self) -> Flt {
+        Rng::gen(self)
+    }
+}
+
+// random permutation of [0, l) indexed by p, see Kensler, "Correlated Multi-Jittered Sampling"
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    i.wrapping_add(p) % l
+}
+
+// every dimension is stratified into spp strata which are shuffled per pixel and dimension
+pub struct Stratified {
+    spp: u32,
+    jitter: bool,
+    pixel: u64,
+    index: u32,
+    dim: u64,
+    rng: Rng,
+}
+
+impl Stratified {
+    pub fn new(spp: usize, jitter: bool) -> Self {
+        let spp = spp.max(1) as u32;
+        Self { spp, jitter, pixel: 0, index: 0, dim: 0, rng: Rng::new(0) }
+    }
+}
+
+impl Sampler for Stratified {
+    fn start(&mut self, pixel: usize, index: usize) {
+        self.pixel = pixel as u64;
+        self.index = (index as u32) % self.spp;
+        self.dim = 0;
+        self.rng = Rng::new(hash2(pixel as u64, index as u64));
+    }
+
+    fn gen(&mut self) -> Flt {
+        let p = hash2(self.pixel, self.dim);
+        self.dim += 1;
+        let s = permute(self.index, self.spp, p);
+        let j = if self.jitter { self.rng.gen() } else { 0.5 };
+        ((s as Flt + j) / self.spp as Flt).min(1.0 - INV32)
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// Halton sequence with a random shift per pixel and dimension (Cranley-Patterson rotation),
+// dimensions beyond the prime table fall back to independent random numbers
+pub struct Halton {
+    pixel: u64,
+    index: u64,
+    dim: usize,
+    rng: Rng,
+}
+
+impl Halton {
+    pub fn new() -> Self {
+        Self { pixel: 0, index: 0, dim: 0, rng: Rng::new(0) }
+    }
+
+    pub fn radical_inverse(base: u32, mut a: u64) -> Flt {
+        let (base, inv) = (base as u64, 1.0 / base as Flt);
+        let (mut ret, mut f) = (0.0, inv);
+        while a > 0 {
+            ret += (a % base) as Flt * f;
+            a /= base;
+            f *= inv;
+        }
+        ret
+    }
+}
+
+impl Default for Halton {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sampler for Halton {
+    fn start(&mut self, pixel: usize, index: usize) {
+        self.pixel = pixel as u64;
+        self.index = index as u64;
+        self.dim = 0;
+        self.rng = Rng::new(hash2(pixel as u64, index as u64));
+    }
+
+    fn gen(&mut self) -> Flt {
+        let dim = self.dim;
+        self.dim += 1;
+        if dim >= PRIMES.len() {
+            return self.rng.gen();
+        }
+        let shift = hash2(self.pixel, dim as u64) as Flt * INV32;
+        let x = Self::radical_inverse(PRIMES[dim], self.index) + shift;
+        (x - x.floor()).min(1.0 - INV32)
+    }
+}
+
+// Owen-scrambled Sobol, every pair of dimensions is an independently scrambled 2D Sobol
+// sequence, see Burley, "Practical Hash-based Owen Scrambling"
+pub struct Sobol {
+    pixel: u64,
+    index: u32,
+    dim: u64,
+    next: Flt,
+}
+
+impl Sobol {
+    pub fn new() -> Self {
+        Self { pixel: 0, index: 0, dim: 0, next: 0.0 }
+    }
+
+    fn laine_karras(mut x: u32, seed: u32) -> u32 {
+        x = x.wrapping_add(seed);
+        x ^= x.wrapping_mul(0x6c50b47c);
+        x ^= x.wrapping_mul(0xb82f1e52);
+        x ^= x.wrapping_mul(0xc7afe638);
+        x ^= x.wrapping_mul(0x8d22f6e6);
+        x
+    }
+
+    pub fn scramble(x: u32, seed: u32) -> u32 {
+        Self::laine_karras(x.reverse_bits(), seed).reverse_bits()
+    }
+
+    // the first two dimensions of the Sobol sequence
+    pub fn sobol2d(index: u32) -> (u32, u32) {
+        let (mut x, mut y, mut v) = (0, 0, 1u32 << 31);
+        for k in 0..32 {
+            if index >> k & 1 == 1 {
+                x ^= 1u32 << (31 - k);
+                y ^= v;
+            }
+            v ^= v >> 1;
+        }
+        (x, y)
+    }
+}
+
+impl Default for Sobol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sampler for Sobol {
+    fn start(&mut self, pixel: usize, index: usize) {
+        self.pixel = pixel as u64;
+        self.index = index as u32;
+        self.dim = 0;
+    }
+
+    fn gen(&mut self) -> Flt {
+        let dim = self.dim;
+        self.dim += 1;
+        if dim & 1 == 1 {
+            return self.next;
+        }
+        let seed = hash2(self.pixel, dim);
+        let index = Self::scramble(self.index, seed);
+        let (x, y) = Self::sobol2d(index);
+        let x = Self::scramble(x, hash(seed as u64) as u32);
+        let y = Self::scramble(y, hash(seed as u64 + 1) as u32);
+        self.next = (y >> 8) as Flt / (1u32 << 24) as Flt;
+        (x >> 8) as Flt / (1u32 << 24) as Flt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sobol2d() {
+        let expect = [(0.0, 0.0), (0.5, 0.5), (0.25, 0.75), (0.75, 0.25), (0.125, 0.625)];
+        for (i, e) in expect.iter().enumerate() {
+            let (x, y) = Sobol::sobol2d(i as u32);
+            assert_eq!((x as Flt * INV32, y as Flt * INV32), *e);
+        }
+    }
+
+    #[test]
+    fn stratified() {
+        for tp in [SamplerType::Stratified { jitter: true }, SamplerType::Sobol].iter() {
+            let n = 16;
+            let mut s = tp.build(n);
+            let mut cnt = vec![[0; 16]; 4];
+            for i in 0..n {
+                s.start(3, i);
+                for c in cnt.iter_mut() {
+                    let x = s.gen();
+                    assert!((0.0..1.0).contains(&x));
+                    c[(x * n as Flt) as usize] += 1;
+                }
+            }
+            assert!(cnt.iter().all(|c| c.iter().all(|&x| x == 1)));
+        }
+    }
+
+    #[test]
+    fn halton() {
+        assert_eq!(Halton::radical_inverse(2, 3), 0.75);
+        assert!((Halton::radical_inverse(3, 5) - 7.0 / 9.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{linalg::Vct, Flt};
+use std::sync::OnceLock;
+
+// the visible range in nanometers
+pub const LAMBDA_MIN: Flt = 360.0;
+pub const LAMBDA_MAX: Flt = 830.0;
+
+// Wavelengths are carried by paths three at a time in a Vct, the hero wavelength x and two
+// more stratified with it, see Wilkie et al., "Hero Wavelength Spectral Sampling". They follow
+// the visible distribution of pbrt, which avoids the ends where the eye is not sensitive.
+// Spectral values at these wavelengths are Vcts as well.
+pub fn sample_wavelengths(u: Flt) -> Vct {
+    let at = |i: Flt| {
+        let u = (u + i / 3.0).fract();
+        538.0 - 138.888889 * (0.85691062 - 1.82750197 * u).atanh()
+    };
+    Vct::new(at(0.0), at(1.0), at(2.0))
+}
+
+fn pdf(lambda: Flt) -> Flt {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.0;
+    }
+    0.0039398042 / (0.0072 * (lambda - 538.0)).cosh().powi(2)
+}
+
+// CIE 1931 color matching functions, fitted by piecewise gaussians in Wyman et al., "Simple
+// Analytic Approximations to the CIE XYZ Color Matching Functions"
+pub fn cmf(lambda: Flt) -> Vct {
+    let g = |mu: Flt, s1: Flt, s2: Flt| {
+        let t = (lambda - mu) / if lambda < mu { s1 } else { s2 };
+        (-0.5 * t * t).exp()
+    };
+    Vct::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+// linear sRGB with a D65 white
+pub fn xyz_to_rgb(c: Vct) -> Vct {
+    Vct::new(
+        3.2404542 * c.x - 1.5371385 * c.y - 0.4985314 * c.z,
+        -0.9692660 * c.x + 1.8760108 * c.y + 0.0415560 * c.z,
+        0.0556434 * c.x - 0.2040259 * c.y + 1.0572252 * c.z,
+    )
+}
+
+// Smooth red, green and blue spectra adding up to 1, RGB values are upsampled to their
+// combination having the same color. Colors are balanced so that the flat spectrum is white,
+// which is then upsampled to itself.
+fn basis(lambda: Flt) -> Vct {
+    let s = |mu: Flt| 1.0 / (1.0 + (-(lambda - mu) / 10.0).exp());
+    let (b, r) = (1.0 - s(490.0), s(590.0));
+    Vct::new(r, 1.0 - b - r, b)
+}
+
+struct Table {
+    y: Flt,        // the integral of the y matching function
+    white: Vct,    // the RGB of the flat spectrum
+    inv: [Vct; 3], // rows of the inverse of the matrix whose columns are the RGB of the basis
+}
+
+fn table() -> &'static Table {
+    static TABLE: OnceLock<Table> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let (mut y, mut xyz) = (0.0, [Vct::zero(); 3]);
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            let (c, b) = (cmf(lambda), basis(lambda));
+            y += c.y;
+            xyz.iter_mut().enumerate().for_each(|(i, v)| *v += c * b[i]);
+            lambda += 1.0;
+        }
+        let white = xyz_to_rgb((xyz[0] + xyz[1] + xyz[2]) / y);
+        let m: Vec<Vct> = xyz.iter().map(|&v| xyz_to_rgb(v / y) / white).collect();
+        // the columns of m are the RGB of the basis, invert it by cofactors
+        let (c0, c1, c2) = (m[0], m[1], m[2]);
+        let (r0, r1, r2) = (c1 % c2, c2 % c0, c0 % c1);
+        let det = c0.dot(r0);
+        Table { y, white, inv: [r0 / det, r1 / det, r2 / det] }
+    })
+}
+
+// the value at lambda of a spectrum having the color c
+pub fn upsample(c: Vct, lambda: Flt) -> Flt {
+    let t = table();
+    let w = Vct::new(t.inv[0].dot(c), t.inv[1].dot(c), t.inv[2].dot(c));
+    w.dot(basis(lambda)).max(0.0)
+}
+
+// a color at the wavelengths of a path, unchanged in RGB mode
+pub fn spectral(c: Vct, lambda: Option<Vct>) -> Vct {
+    match lambda {
+        Some(l) => Vct::new(upsample(c, l.x), upsample(c, l.y), upsample(c, l.z)),
+        None => c,
+    }
+}
+
+// the RGB estimate of the light carried at the wavelengths of a path
+pub fn to_rgb(v: Vct, lambda: Vct) -> Vct {
+    let at = |v: Flt, l: Flt| {
+        let p = pdf(l);
+        if p > 0.0 {
+            cmf(l) * (v / p)
+        } else {
+            Vct::zero()
+        }
+    };
+    let xyz = at(v.x, lambda.x) + at(v.y, lambda.y) + at(v.z, lambda.z);
+    let t = table();
+    xyz_to_rgb(xyz / (3.0 * t.y)) / t.white
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Rng;
+
+    #[test]
+    fn round_trip() {
+        for &l in [400.0, 550.0, 700.0].iter() {
+            assert!((upsample(Vct::one(), l) - 1.0).abs() < 1e-6);
+        }
+        let mut rng = Rng::new(5);
+        for &c in [Vct::one(), Vct::new(0.2, 0.5, 0.8), Vct::new(0.6, 0.3, 0.1)].iter() {
+            let n = 200000;
+            let mut sum = Vct::zero();
+            for _ in 0..n {
+                let l = sample_wavelengths(rng.gen());
+                sum += to_rgb(spectral(c, Some(l)), l);
+            }
+            let d = sum / n as Flt - c;
+            assert!(d.x.abs().max(d.y.abs().max(d.z.abs())) < 0.01, "{:?} {:?}", c, d);
+        }
+    }
+}